@@ -1,4 +1,5 @@
 use std::cmp::Ord;
+use std::fmt;
 use std::time::Duration;
 
 pub fn median<T: Ord + Copy>(vec: &[T]) -> T {
@@ -8,14 +9,150 @@ pub fn median<T: Ord + Copy>(vec: &[T]) -> T {
 }
 
 pub fn mean(vec: &[Duration]) -> Duration {
-    let accum = vec.iter().map(|e| *e).reduce(|acc, e| acc + e);
+    let accum = vec.iter().copied().reduce(|acc, e| acc + e);
     accum.unwrap() / (vec.len() as u32)
 }
 
+/// Nearest-rank percentile of an already sorted slice
+pub fn percentile<T: Copy>(sorted: &[T], percentile: f64) -> T {
+    // the epsilon keeps e.g. 99.9% of 1000 samples from rounding up to the next rank
+    let rank = (percentile / 100.0 * sorted.len() as f64 - 1e-9).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub fn std_dev(vec: &[Duration]) -> Duration {
+    let mean = mean(vec).as_secs_f64();
+    let variance = vec
+        .iter()
+        .map(|e| (e.as_secs_f64() - mean).powi(2))
+        .sum::<f64>()
+        / vec.len() as f64;
+    Duration::from_secs_f64(variance.sqrt())
+}
+
+pub struct Summary {
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub std_dev: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+}
+
+impl Summary {
+    /// Returns `None` if there are no samples
+    pub fn new(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+
+        Some(Self {
+            count: sorted.len(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: mean(&sorted),
+            std_dev: std_dev(&sorted),
+            p50: median(&sorted),
+            p90: percentile(&sorted, 90.0),
+            p99: percentile(&sorted, 99.0),
+            p999: percentile(&sorted, 99.9),
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Samples: {}", self.count)?;
+        writeln!(
+            f,
+            "Min: {:#?}, Mean: {:#?}, Max: {:#?}, Std dev: {:#?}",
+            self.min, self.mean, self.max, self.std_dev
+        )?;
+        write!(
+            f,
+            "p50: {:#?}, p90: {:#?}, p99: {:#?}, p99.9: {:#?}",
+            self.p50, self.p90, self.p99, self.p999
+        )
+    }
+}
+
+pub struct Histogram {
+    bucket_width: Duration,
+    first_bucket: u32,
+    /// Samples per bucket, the last bucket also collects everything beyond `MAX_BUCKETS`
+    buckets: Vec<usize>,
+}
+
+impl Histogram {
+    const MAX_BUCKETS: usize = 50;
+    const BAR_WIDTH: usize = 50;
+
+    pub fn new(samples: &[Duration], bucket_width: Duration) -> Self {
+        let bucket_index = |sample: &Duration| (sample.as_nanos() / bucket_width.as_nanos()) as u32;
+
+        let first_bucket = samples.iter().map(bucket_index).min().unwrap_or(0);
+        let last_bucket = samples.iter().map(bucket_index).max().unwrap_or(0);
+        let bucket_count = ((last_bucket - first_bucket) as usize + 1).min(Self::MAX_BUCKETS);
+
+        let mut buckets = vec![0; bucket_count];
+        for sample in samples {
+            let index = (bucket_index(sample) - first_bucket) as usize;
+            buckets[index.min(bucket_count - 1)] += 1;
+        }
+
+        Self {
+            bucket_width,
+            first_bucket,
+            buckets,
+        }
+    }
+
+    fn is_truncated(&self) -> bool {
+        self.buckets.len() == Self::MAX_BUCKETS
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let largest = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+
+        for (i, count) in self.buckets.iter().enumerate() {
+            let lower = self.bucket_width * (self.first_bucket + i as u32);
+            let bar = "#".repeat(count * Self::BAR_WIDTH / largest);
+            if self.is_truncated() && i == self.buckets.len() - 1 {
+                writeln!(
+                    f,
+                    "{:>12} {:>12} | {} {}",
+                    ">=",
+                    format!("{:#?}", lower),
+                    bar,
+                    count
+                )?;
+            } else {
+                let upper = lower + self.bucket_width;
+                writeln!(
+                    f,
+                    "{:>12} {:>12} | {} {}",
+                    format!("{:#?}", lower),
+                    format!("{:#?}", upper),
+                    bar,
+                    count
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::analysis::mean;
-    use crate::analysis::median;
+    use crate::analysis::{mean, median, percentile, Histogram, Summary};
     use crate::Duration;
 
     #[test]
@@ -32,4 +169,50 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<u32> = (1..=1000).collect();
+        assert_eq!(percentile(&sorted, 50.0), 500);
+        assert_eq!(percentile(&sorted, 90.0), 900);
+        assert_eq!(percentile(&sorted, 99.9), 999);
+        assert_eq!(percentile(&sorted, 100.0), 1000);
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&[7], 99.0), 7);
+    }
+
+    #[test]
+    fn test_summary() {
+        assert!(Summary::new(&[]).is_none());
+
+        let samples: Vec<_> = [4, 2, 6, 4, 4, 5, 5, 2]
+            .iter()
+            .map(|x| Duration::from_millis(*x))
+            .collect();
+        let summary = Summary::new(&samples).unwrap();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.min, Duration::from_millis(2));
+        assert_eq!(summary.max, Duration::from_millis(6));
+        assert_eq!(summary.mean, Duration::from_millis(4));
+        assert_eq!(summary.std_dev.as_micros(), 1322);
+        assert_eq!(summary.p50, Duration::from_millis(4));
+        assert_eq!(summary.p99, Duration::from_millis(6));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let samples: Vec<_> = [12, 15, 21, 38]
+            .iter()
+            .map(|x| Duration::from_micros(*x))
+            .collect();
+        let histogram = Histogram::new(&samples, Duration::from_micros(10));
+        assert_eq!(histogram.first_bucket, 1);
+        assert_eq!(histogram.buckets, [2, 1, 1]);
+        assert_eq!(histogram.to_string().lines().count(), 3);
+
+        let histogram = Histogram::new(&samples, Duration::from_nanos(100));
+        assert!(histogram.is_truncated());
+        assert_eq!(histogram.buckets.iter().sum::<usize>(), 4);
+        assert_eq!(histogram.buckets[49], 2);
+    }
 }
@@ -0,0 +1,650 @@
+use crate::analysis::{Histogram, Summary};
+use crate::transport::{OutputConnection, Transport};
+use crate::utils::wait_for_sigint;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Builder;
+use tokio::sync::oneshot;
+
+pub const TICKS_PER_QUARTER: u32 = 24;
+
+const TIMING_CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const CONTINUE: u8 = 0xfb;
+const STOP: u8 = 0xfc;
+const SONG_POSITION: u8 = 0xf2;
+
+/// Tick intervals kept for the statistics, about 20 minutes at 120 BPM
+const MAX_INTERVALS: usize = 1 << 16;
+
+pub fn tick_interval(bpm: f64) -> Duration {
+    Duration::from_secs_f64(60.0 / (bpm * TICKS_PER_QUARTER as f64))
+}
+
+/// Tempo derived from the mean tick interval
+fn bpm(intervals: &[Duration]) -> Option<f64> {
+    let summary = Summary::new(intervals)?;
+    Some(60.0 / (summary.mean.as_secs_f64() * TICKS_PER_QUARTER as f64))
+}
+
+/// Deviation of each tick interval from the mean interval
+fn jitter(intervals: &[Duration]) -> Vec<Duration> {
+    match Summary::new(intervals) {
+        Some(summary) => intervals
+            .iter()
+            .map(|interval| interval.abs_diff(summary.mean))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransportState {
+    #[default]
+    Stopped,
+    Playing,
+}
+
+/// Measures the intervals between clock ticks and follows the transport messages. The statistics
+/// cover the last `MAX_INTERVALS` intervals since the latest Start or Continue; ticks after a Stop
+/// are counted, but not measured.
+#[derive(Default)]
+pub struct ClockAnalyser {
+    ticks: usize,
+    /// Backend timestamp of the previous tick in microseconds, `None` after a Stop
+    last_tick: Option<u64>,
+    /// A Stop was received and no Start or Continue since
+    stopped: bool,
+    intervals: VecDeque<Duration>,
+    transport_state: TransportState,
+    /// Song position in sixteenth notes
+    song_position: u16,
+    /// Ticks since the last sixteenth note
+    sixteenth_ticks: u32,
+}
+
+impl ClockAnalyser {
+    pub fn process(&mut self, stamp: u64, message: &[u8]) {
+        match *message {
+            [TIMING_CLOCK] => {
+                self.ticks += 1;
+                if self.stopped {
+                    return;
+                }
+                if let Some(last_tick) = self.last_tick.replace(stamp) {
+                    if self.intervals.len() == MAX_INTERVALS {
+                        self.intervals.pop_front();
+                    }
+                    self.intervals
+                        .push_back(Duration::from_micros(stamp.saturating_sub(last_tick)));
+                }
+                if self.transport_state == TransportState::Playing {
+                    self.sixteenth_ticks += 1;
+                    if self.sixteenth_ticks == TICKS_PER_QUARTER / 4 {
+                        self.sixteenth_ticks = 0;
+                        self.song_position = self.song_position.wrapping_add(1);
+                    }
+                }
+            }
+            [START] => {
+                self.start_segment();
+                self.song_position = 0;
+                self.sixteenth_ticks = 0;
+            }
+            [CONTINUE] => self.start_segment(),
+            [STOP] => {
+                self.transport_state = TransportState::Stopped;
+                self.stopped = true;
+                self.last_tick = None;
+            }
+            [SONG_POSITION, lsb, msb] => {
+                self.song_position = (lsb & 0x7f) as u16 | ((msb & 0x7f) as u16) << 7;
+                self.sixteenth_ticks = 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn start_segment(&mut self) {
+        self.transport_state = TransportState::Playing;
+        self.stopped = false;
+        self.last_tick = None;
+        self.intervals.clear();
+    }
+
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+
+    pub fn transport_state(&self) -> TransportState {
+        self.transport_state
+    }
+
+    pub fn song_position(&self) -> u16 {
+        self.song_position
+    }
+
+    pub fn intervals(&self) -> Vec<Duration> {
+        self.intervals.iter().copied().collect()
+    }
+
+    /// The last `count` tick intervals
+    pub fn recent_intervals(&self, count: usize) -> Vec<Duration> {
+        let first = self.intervals.len().saturating_sub(count);
+        self.intervals.iter().skip(first).copied().collect()
+    }
+
+    /// Tempo derived from the mean tick interval
+    pub fn bpm(&self) -> Option<f64> {
+        bpm(&self.intervals())
+    }
+
+    /// Deviation of each tick interval from the mean interval
+    pub fn jitter(&self) -> Vec<Duration> {
+        jitter(&self.intervals())
+    }
+
+    pub fn print_analysis(&self, histogram_bucket_width: Duration) {
+        println!("Ticks: {}", self.ticks());
+        match self.bpm() {
+            Some(bpm) => println!("Tempo: {:.2} BPM", bpm),
+            None => {
+                println!("Not enough ticks received");
+                return;
+            }
+        }
+
+        println!("Tick interval:");
+        println!("{}", Summary::new(&self.intervals()).unwrap());
+        println!("Jitter:");
+        let jitter = self.jitter();
+        println!("{}", Summary::new(&jitter).unwrap());
+        print!("{}", Histogram::new(&jitter, histogram_bucket_width));
+    }
+}
+
+pub struct ClockOptions {
+    pub bpm: f64,
+    /// Song position (in sixteenth notes) to continue from, instead of starting from the beginning
+    pub position: Option<u16>,
+    pub histogram_bucket_width: Duration,
+}
+
+/// Sends the clock until `running` is cleared, each tick is scheduled relative to the start, so
+/// that scheduling delays do not accumulate
+fn send_clock(
+    mut output: Box<dyn OutputConnection>,
+    interval: Duration,
+    position: Option<u16>,
+    running: &AtomicBool,
+) -> Result<u64, String> {
+    let mut send = |message: &[u8]| output.send(message).map_err(|e| e.to_string());
+
+    match position {
+        Some(position) => {
+            send(&[
+                SONG_POSITION,
+                (position & 0x7f) as u8,
+                ((position >> 7) & 0x7f) as u8,
+            ])?;
+            send(&[CONTINUE])?;
+        }
+        None => send(&[START])?,
+    }
+
+    let start = Instant::now();
+    let mut ticks = 0u64;
+    while running.load(Ordering::Relaxed) {
+        let deadline = start + interval.mul_f64(ticks as f64);
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        send(&[TIMING_CLOCK])?;
+        ticks += 1;
+    }
+
+    send(&[STOP])?;
+    Ok(ticks)
+}
+
+fn run_clock(
+    transport: &dyn Transport,
+    options: &ClockOptions,
+    output_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if options.bpm <= 0.0 || !options.bpm.is_finite() {
+        return Err(Box::from("Tempo must be positive"));
+    }
+    let output = transport.connect_output(output_device)?;
+    let interval = tick_interval(options.bpm);
+    let position = options.position;
+    let running = Arc::new(AtomicBool::new(true));
+
+    // tokio timers only have millisecond resolution, so the clock runs on its own thread
+    let captured_running = running.clone();
+    let (finished_sender, finished) = oneshot::channel::<()>();
+    let clock_thread = thread::spawn(move || {
+        let result = send_clock(output, interval, position, &captured_running);
+        drop(finished_sender);
+        result
+    });
+
+    // the thread only finishes by itself on a send error
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        tokio::select! {
+            _ = stop => {},
+            _ = finished => {},
+        }
+    });
+    running.store(false, Ordering::Relaxed);
+
+    let ticks = clock_thread.join().map_err(|_| "Clock thread panicked")??;
+    Ok(ticks)
+}
+
+pub fn clock(
+    transport: &dyn Transport,
+    options: &ClockOptions,
+    output_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ticks = run_clock(transport, options, output_device, wait_for_sigint())?;
+    println!("Sent {} ticks", ticks);
+    Ok(())
+}
+
+fn run_clock_test(
+    transport: &dyn Transport,
+    options: &ClockOptions,
+    input_device: &str,
+    output_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<ClockAnalyser, Box<dyn std::error::Error>> {
+    let analyser = Arc::new(Mutex::new(ClockAnalyser::default()));
+
+    let captured_analyser = analyser.clone();
+    let in_connection = transport.connect_input(
+        input_device,
+        Box::new(move |stamp, message: &[u8]| {
+            captured_analyser.lock().unwrap().process(stamp, message)
+        }),
+    )?;
+
+    run_clock(transport, options, output_device, stop)?;
+    drop(in_connection);
+
+    let analyser = std::mem::take(&mut *analyser.lock().unwrap());
+    Ok(analyser)
+}
+
+pub fn clock_and_analyse(
+    transport: &dyn Transport,
+    options: &ClockOptions,
+    input_device: &str,
+    output_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let analyser = run_clock_test(
+        transport,
+        options,
+        input_device,
+        output_device,
+        wait_for_sigint(),
+    )?;
+
+    println!("Nominal tempo: {:.2} BPM", options.bpm);
+    analyser.print_analysis(options.histogram_bucket_width);
+    Ok(())
+}
+
+pub struct MonitorOptions {
+    pub report_interval: Duration,
+    /// Tick intervals the reported tempo and jitter are computed from
+    pub window: usize,
+    pub histogram_bucket_width: Duration,
+}
+
+/// Song position as bar.beat.sixteenth, assuming 4/4
+fn format_position(song_position: u16) -> String {
+    format!(
+        "{}.{}.{}",
+        song_position / 16 + 1,
+        song_position % 16 / 4 + 1,
+        song_position % 4 + 1
+    )
+}
+
+fn print_status(analyser: &ClockAnalyser, window: usize, new_ticks: usize) {
+    let state = match analyser.transport_state() {
+        TransportState::Playing => "Playing",
+        TransportState::Stopped => "Stopped",
+    };
+    let intervals = analyser.recent_intervals(window);
+    let tempo = match (new_ticks, bpm(&intervals)) {
+        (0, _) => format!("{:>8}", "no clock"),
+        (_, Some(bpm)) => format!("{:>8.2}", bpm),
+        (_, None) => format!("{:>8}", "-"),
+    };
+    let jitter = match Summary::new(&jitter(&intervals)) {
+        Some(summary) => format!(
+            "{:>12} {:>12} {:>12}",
+            format!("{:#?}", summary.p50),
+            format!("{:#?}", summary.p99),
+            format!("{:#?}", summary.max)
+        ),
+        None => format!("{:>12} {:>12} {:>12}", "-", "-", "-"),
+    };
+    println!(
+        "{:>8} {:>10} {} {:>8} {}",
+        state,
+        format_position(analyser.song_position()),
+        tempo,
+        analyser.ticks(),
+        jitter
+    );
+}
+
+fn run_clock_monitor(
+    transport: &dyn Transport,
+    options: &MonitorOptions,
+    input_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<ClockAnalyser, Box<dyn std::error::Error>> {
+    let analyser = Arc::new(Mutex::new(ClockAnalyser::default()));
+
+    let captured_analyser = analyser.clone();
+    let in_connection = transport.connect_input(
+        input_device,
+        Box::new(move |stamp, message: &[u8]| {
+            captured_analyser.lock().unwrap().process(stamp, message)
+        }),
+    )?;
+
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        let report = async {
+            let mut reports = tokio::time::interval(options.report_interval);
+            reports.tick().await;
+            let mut last_ticks = 0;
+            loop {
+                reports.tick().await;
+                let analyser = analyser.lock().unwrap();
+                print_status(&analyser, options.window, analyser.ticks() - last_ticks);
+                last_ticks = analyser.ticks();
+            }
+        };
+
+        tokio::select! {
+            _ = report => {},
+            _ = stop => {},
+        }
+    });
+    drop(in_connection);
+
+    let analyser = std::mem::take(&mut *analyser.lock().unwrap());
+    Ok(analyser)
+}
+
+pub fn clock_monitor(
+    transport: &dyn Transport,
+    options: &MonitorOptions,
+    input_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "{:>8} {:>10} {:>8} {:>8} {:>12} {:>12} {:>12}",
+        "State", "Position", "BPM", "Ticks", "Jitter p50", "p99", "Max"
+    );
+    let analyser = run_clock_monitor(transport, options, input_device, wait_for_sigint())?;
+
+    analyser.print_analysis(options.histogram_bucket_width);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{
+        format_position, run_clock, run_clock_monitor, run_clock_test, tick_interval,
+        ClockAnalyser, ClockOptions, MonitorOptions, TransportState, MAX_INTERVALS,
+    };
+    use crate::transport::{
+        InputCallback, InputConnection, LoopbackTransport, OutputConnection, Transport,
+    };
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    /// Transport whose outputs fail to send
+    struct FailingTransport;
+
+    struct FailingOutput;
+
+    impl OutputConnection for FailingOutput {
+        fn send(&mut self, _message: &[u8]) -> Result<(), Box<dyn Error>> {
+            Err(Box::from("Device unplugged"))
+        }
+    }
+
+    impl Transport for FailingTransport {
+        fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(Vec::new())
+        }
+
+        fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(Vec::new())
+        }
+
+        fn connect_input(
+            &self,
+            _port_name: &str,
+            _callback: InputCallback,
+        ) -> Result<InputConnection, Box<dyn Error>> {
+            Err(Box::from("No inputs"))
+        }
+
+        fn connect_output(
+            &self,
+            _port_name: &str,
+        ) -> Result<Box<dyn OutputConnection>, Box<dyn Error>> {
+            Ok(Box::new(FailingOutput))
+        }
+    }
+
+    #[test]
+    fn test_clock_analyser() {
+        let mut analyser = ClockAnalyser::default();
+        analyser.process(0, &[0xfa]);
+        for stamp in [0, 20_000, 41_000, 60_000, 80_000] {
+            analyser.process(stamp, &[0xf8]);
+        }
+
+        assert_eq!(analyser.ticks(), 5);
+        assert_eq!(analyser.intervals()[1], Duration::from_millis(21));
+        assert!((analyser.bpm().unwrap() - 125.0).abs() < 1e-6);
+        assert_eq!(
+            analyser.jitter(),
+            [
+                Duration::ZERO,
+                Duration::from_millis(1),
+                Duration::from_millis(1),
+                Duration::ZERO
+            ]
+        );
+        assert_eq!(tick_interval(125.0), Duration::from_millis(20));
+        assert_eq!(
+            analyser.recent_intervals(2),
+            [Duration::from_millis(19), Duration::from_millis(20)]
+        );
+        assert_eq!(analyser.recent_intervals(10).len(), 4);
+    }
+
+    #[test]
+    fn test_intervals_across_stop() {
+        let mut analyser = ClockAnalyser::default();
+        analyser.process(0, &[0xfa]);
+        analyser.process(0, &[0xf8]);
+        analyser.process(20_000, &[0xf8]);
+        analyser.process(30_000, &[0xfc]);
+        assert_eq!(analyser.intervals(), [Duration::from_millis(20)]);
+
+        // ticks while stopped are not measured
+        analyser.process(40_000, &[0xf8]);
+        analyser.process(45_000, &[0xf8]);
+        assert_eq!(analyser.intervals(), [Duration::from_millis(20)]);
+
+        // a new segment starts at a different tempo
+        analyser.process(5_000_000, &[0xfb]);
+        analyser.process(5_000_000, &[0xf8]);
+        analyser.process(5_010_000, &[0xf8]);
+        analyser.process(5_020_000, &[0xf8]);
+        assert_eq!(analyser.ticks(), 7);
+        assert_eq!(
+            analyser.intervals(),
+            [Duration::from_millis(10), Duration::from_millis(10)]
+        );
+    }
+
+    #[test]
+    fn test_bounded_intervals() {
+        let mut analyser = ClockAnalyser::default();
+        for tick in 0..MAX_INTERVALS as u64 + 10 {
+            analyser.process(tick * 20_000, &[0xf8]);
+        }
+        assert_eq!(analyser.ticks(), MAX_INTERVALS + 10);
+        assert_eq!(analyser.intervals().len(), MAX_INTERVALS);
+        assert!((analyser.bpm().unwrap() - 125.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_clock_send_error() {
+        let result = run_clock(
+            &FailingTransport,
+            &ClockOptions {
+                bpm: 120.0,
+                position: None,
+                histogram_bucket_width: Duration::from_micros(10),
+            },
+            "clock",
+            std::future::pending(),
+        );
+        assert_eq!(result.unwrap_err().to_string(), "Device unplugged");
+    }
+
+    #[test]
+    fn test_transport_state() {
+        let mut analyser = ClockAnalyser::default();
+        assert_eq!(analyser.transport_state(), TransportState::Stopped);
+
+        analyser.process(0, &[0xfa]);
+        for _ in 0..13 {
+            analyser.process(0, &[0xf8]);
+        }
+        assert_eq!(analyser.transport_state(), TransportState::Playing);
+        assert_eq!(analyser.song_position(), 2);
+
+        analyser.process(0, &[0xfc]);
+        analyser.process(0, &[0xf8]);
+        assert_eq!(analyser.transport_state(), TransportState::Stopped);
+        assert_eq!(analyser.song_position(), 2);
+
+        analyser.process(0, &[0xf2, 0x01, 0x02]);
+        analyser.process(0, &[0xfb]);
+        assert_eq!(analyser.transport_state(), TransportState::Playing);
+        assert_eq!(analyser.song_position(), 257);
+        assert_eq!(format_position(257), "17.1.2");
+        assert_eq!(format_position(0), "1.1.1");
+    }
+
+    #[test]
+    fn test_clock_monitor() {
+        let transport = LoopbackTransport::new();
+        let clock_transport = transport.clone();
+        let clock = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            run_clock(
+                &clock_transport,
+                &ClockOptions {
+                    bpm: 300.0,
+                    position: None,
+                    histogram_bucket_width: Duration::from_micros(10),
+                },
+                "clock",
+                async { sleep(Duration::from_millis(200)).await },
+            )
+            .unwrap()
+        });
+
+        let analyser = run_clock_monitor(
+            &transport,
+            &MonitorOptions {
+                report_interval: Duration::from_millis(50),
+                window: 24,
+                histogram_bucket_width: Duration::from_micros(10),
+            },
+            "clock",
+            async { sleep(Duration::from_millis(300)).await },
+        )
+        .unwrap();
+
+        let ticks = clock.join().unwrap();
+        assert_eq!(analyser.ticks() as u64, ticks);
+        assert_eq!(analyser.transport_state(), TransportState::Stopped);
+        assert!((analyser.bpm().unwrap() - 300.0).abs() < 15.0);
+    }
+
+    #[test]
+    fn test_clock() {
+        let transport = LoopbackTransport::new();
+        let analyser = run_clock_test(
+            &transport,
+            &ClockOptions {
+                bpm: 600.0,
+                position: None,
+                histogram_bucket_width: Duration::from_micros(10),
+            },
+            "loop",
+            "loop",
+            async { sleep(Duration::from_millis(200)).await },
+        )
+        .unwrap();
+
+        assert!(analyser.ticks() >= 40);
+        assert!((analyser.bpm().unwrap() - 600.0).abs() < 30.0);
+    }
+
+    #[test]
+    fn test_clock_transport() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let _input = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_stamp, message: &[u8]| {
+                    captured.lock().unwrap().push(message.to_vec())
+                }),
+            )
+            .unwrap();
+
+        run_clock_test(
+            &transport,
+            &ClockOptions {
+                bpm: 120.0,
+                position: Some(200),
+                histogram_bucket_width: Duration::from_micros(10),
+            },
+            "loop",
+            "loop",
+            async { sleep(Duration::from_millis(50)).await },
+        )
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0], [0xf2, 200 & 0x7f, 1]);
+        assert_eq!(received[1], [0xfb]);
+        assert_eq!(received[2], [0xf8]);
+        assert_eq!(received.last().unwrap(), &[0xfc]);
+    }
+}
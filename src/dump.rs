@@ -1,24 +1,657 @@
-use crate::utils::{loop_until_sigint, resolve_input_port};
-use midir::{Ignore, MidiInput};
+use crate::report::{json_object, json_string};
+use crate::smf::SmfWriter;
+use crate::transport::{InputConnection, Transport};
+use crate::utils::{self, wait_for_sigint};
+use clap::ValueEnum;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Builder;
+use tokio::sync::Notify;
+use wmidi::MidiMessage;
 
-fn echo_message(_timestamp: u64, message: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-    let message = wmidi::MidiMessage::try_from(message)?;
-    println!("Received {:#?}", message);
-    Ok(())
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Format {
+    /// One line per message, such as `ch1 NoteOn C4 vel 100`
+    #[default]
+    Compact,
+    /// Raw bytes in hex
+    Hex,
+    /// JSON Lines with timestamp, port, bytes and description
+    Json,
+    /// Multi-line debug representation of the decoded message
+    Pretty,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum TimeField {
+    /// Seconds since the start of the dump
+    Absolute,
+    /// UTC wall clock time
+    Wall,
+    /// Seconds since the previous message
+    Delta,
+}
+
+#[derive(Clone, Default)]
+pub struct DumpOptions {
+    pub format: Format,
+    /// Time fields printed with each message, in this order
+    pub time_fields: Vec<TimeField>,
+    /// Measure deltas from the previous message on the same channel
+    pub per_channel_delta: bool,
+    /// Decimal places of the printed seconds
+    pub precision: usize,
+    /// Stop at the first message that cannot be decoded and fail
+    pub fatal_errors: bool,
+    /// Prefix each line with the name of the port, JSON Lines always contain it
+    pub label_ports: bool,
+}
+
+/// Taken out of the mutex to finalise the file, messages arriving afterwards are not recorded
+type Recorder = Arc<Mutex<Option<SmfWriter<BufWriter<File>>>>>;
+
+fn hex(message: &[u8]) -> String {
+    let bytes: Vec<_> = message.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+fn seconds(duration: Duration, precision: usize) -> String {
+    format!("{:.*}", precision, duration.as_secs_f64())
+}
+
+/// Year, month and day of a day count since 1970-01-01, see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// ISO 8601 UTC time such as `2023-11-14T22:13:20.500Z`
+fn format_wall_clock(time: SystemTime, precision: usize) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let fraction = match precision.min(9) {
+        0 => String::new(),
+        digits => format!(
+            ".{:0digits$}",
+            since_epoch.subsec_nanos() / 10u32.pow(9 - digits as u32)
+        ),
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        fraction
+    )
+}
+
+/// Maps backend timestamps onto the time since the start of the dump
+struct Timekeeper {
+    start: Instant,
+    start_wall_clock: SystemTime,
+    /// Backend timestamp of the start, derived from the first message
+    origin: Option<u64>,
+    /// Timestamps of the previous message per channel, system messages at index 16
+    previous: [Option<u64>; 17],
+}
+
+impl Timekeeper {
+    fn new() -> Self {
+        Timekeeper {
+            start: Instant::now(),
+            start_wall_clock: SystemTime::now(),
+            origin: None,
+            previous: [None; 17],
+        }
+    }
+
+    fn since_start(&mut self, timestamp: u64) -> Duration {
+        let origin = *self.origin.get_or_insert_with(|| {
+            timestamp.saturating_sub(self.start.elapsed().as_micros() as u64)
+        });
+        Duration::from_micros(timestamp.saturating_sub(origin))
+    }
+
+    /// Formatted time fields of a message, `None` for the delta of the first message
+    fn fields(
+        &mut self,
+        options: &DumpOptions,
+        timestamp: u64,
+        message: &[u8],
+    ) -> Vec<(TimeField, Option<String>)> {
+        let since_start = self.since_start(timestamp);
+
+        let slot = match message.first() {
+            Some(status @ 0x80..=0xef) if options.per_channel_delta => (status & 0x0f) as usize,
+            Some(0xf0..=0xff) if options.per_channel_delta => 16,
+            _ => 0,
+        };
+        let delta = self.previous[slot]
+            .map(|previous| Duration::from_micros(timestamp.saturating_sub(previous)));
+        self.previous[slot] = Some(timestamp);
+
+        options
+            .time_fields
+            .iter()
+            .map(|field| {
+                let value = match field {
+                    TimeField::Absolute => Some(seconds(since_start, options.precision)),
+                    TimeField::Wall => Some(format_wall_clock(
+                        self.start_wall_clock + since_start,
+                        options.precision,
+                    )),
+                    TimeField::Delta => delta.map(|delta| seconds(delta, options.precision)),
+                };
+                (*field, value)
+            })
+            .collect()
+    }
 }
 
-pub fn dump(input_device: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut midi_in = MidiInput::new("midi-toolbox input")?;
-    midi_in.ignore(Ignore::None);
+/// One-line description of a message, such as `ch1 NoteOn C4 vel 100`
+fn describe(message: &MidiMessage) -> String {
+    match message {
+        MidiMessage::NoteOff(channel, note, velocity) => format!(
+            "ch{} NoteOff {} vel {}",
+            channel.number(),
+            utils::note_name(*note as u8),
+            u8::from(*velocity)
+        ),
+        MidiMessage::NoteOn(channel, note, velocity) => format!(
+            "ch{} NoteOn {} vel {}",
+            channel.number(),
+            utils::note_name(*note as u8),
+            u8::from(*velocity)
+        ),
+        MidiMessage::PolyphonicKeyPressure(channel, note, pressure) => format!(
+            "ch{} PolyPressure {} {}",
+            channel.number(),
+            utils::note_name(*note as u8),
+            u8::from(*pressure)
+        ),
+        MidiMessage::ControlChange(channel, controller, value) => format!(
+            "ch{} CC {} value {}",
+            channel.number(),
+            u8::from(*controller),
+            u8::from(*value)
+        ),
+        MidiMessage::ProgramChange(channel, program) => {
+            format!(
+                "ch{} ProgramChange {}",
+                channel.number(),
+                u8::from(*program)
+            )
+        }
+        MidiMessage::ChannelPressure(channel, pressure) => {
+            format!(
+                "ch{} ChannelPressure {}",
+                channel.number(),
+                u8::from(*pressure)
+            )
+        }
+        MidiMessage::PitchBendChange(channel, bend) => format!(
+            "ch{} PitchBend {}",
+            channel.number(),
+            u16::from(*bend) as i32 - 8192
+        ),
+        // including the F0 and F7 framing bytes
+        MidiMessage::SysEx(data) => format!("SysEx {} bytes", data.len() + 2),
+        MidiMessage::OwnedSysEx(data) => format!("SysEx {} bytes", data.len() + 2),
+        MidiMessage::MidiTimeCode(value) => {
+            let value = u8::from(*value);
+            format!("MTC quarter frame {} value {}", value >> 4, value & 0x0f)
+        }
+        MidiMessage::SongPositionPointer(position) => {
+            format!("SongPosition {}", u16::from(*position))
+        }
+        MidiMessage::SongSelect(song) => format!("SongSelect {}", u8::from(*song)),
+        MidiMessage::Reserved(status) => format!("Reserved {:02x}", status),
+        message => format!("{:?}", message),
+    }
+}
 
-    let in_port = resolve_input_port(&midi_in, input_device)?;
+/// Formats a message, an `Err` line shows the raw bytes of a message that cannot be decoded
+fn format_message(
+    format: Format,
+    port: &str,
+    label_port: bool,
+    timestamp: u64,
+    times: &[(TimeField, Option<String>)],
+    message: &[u8],
+) -> Result<String, String> {
+    let decoded = MidiMessage::try_from(message);
 
-    let _connection = midi_in.connect(
-        &in_port,
-        "MidiToolbox input",
-        |stamp, message, _| echo_message(stamp, message).expect("Message parse error"),
-        (),
+    if let Format::Json = format {
+        let bytes: Vec<_> = message.iter().map(|byte| byte.to_string()).collect();
+        let mut fields = vec![("timestamp_us", timestamp.to_string())];
+        for (field, value) in times {
+            fields.push(match (field, value) {
+                (TimeField::Absolute, Some(value)) => ("time_s", value.clone()),
+                (TimeField::Wall, Some(value)) => ("wall_clock", json_string(value)),
+                (TimeField::Delta, Some(value)) => ("delta_s", value.clone()),
+                (_, None) => ("delta_s", "null".to_string()),
+            });
+        }
+        fields.extend([
+            ("port", json_string(port)),
+            ("bytes", format!("[{}]", bytes.join(", "))),
+        ]);
+        return match &decoded {
+            Ok(decoded) => {
+                fields.push(("message", json_string(&describe(decoded))));
+                Ok(json_object(&fields))
+            }
+            Err(error) => {
+                fields.push(("message", "null".to_string()));
+                fields.push(("error", json_string(&error.to_string())));
+                Err(json_object(&fields))
+            }
+        };
+    }
+
+    let mut line: Vec<_> = label_port
+        .then(|| format!("[{}]", port))
+        .into_iter()
+        .collect();
+    line.extend(times.iter().map(|(field, value)| match (field, value) {
+        (TimeField::Delta, Some(value)) => format!("+{}", value),
+        (_, Some(value)) => value.clone(),
+        (_, None) => "-".to_string(),
+    }));
+    match (format, &decoded) {
+        (_, Err(error)) => {
+            line.push(format!("{} (cannot decode: {})", hex(message), error));
+            return Err(line.join(" "));
+        }
+        (Format::Hex, _) => line.push(hex(message)),
+        (Format::Pretty, Ok(decoded)) => line.push(format!("Received {:#?}", decoded)),
+        (_, Ok(decoded)) => line.push(describe(decoded)),
+    }
+    Ok(line.join(" "))
+}
+
+/// Parse errors of the dumped messages
+#[derive(Default)]
+struct ParseErrors {
+    count: AtomicU64,
+    /// Notified on each parse error
+    notify: Notify,
+}
+
+/// Prints a message with a single `println!`, which holds the stdout lock for the whole message, so
+/// the lines of concurrently dumped ports do not interleave. Returns whether it could be decoded.
+fn echo_message(
+    options: &DumpOptions,
+    port: &str,
+    timestamp: u64,
+    times: &[(TimeField, Option<String>)],
+    message: &[u8],
+    errors: &ParseErrors,
+) -> bool {
+    let formatted = format_message(
+        options.format,
+        port,
+        options.label_ports,
+        timestamp,
+        times,
+        message,
     );
+    match formatted {
+        Ok(line) => {
+            println!("{}", line);
+            true
+        }
+        Err(line) => {
+            println!("{}", line);
+            errors.count.fetch_add(1, Ordering::Relaxed);
+            errors.notify.notify_one();
+            false
+        }
+    }
+}
+
+fn record_message(recorder: &Recorder, since_start: Duration, message: &[u8]) {
+    if let Some(writer) = recorder.lock().unwrap().as_mut() {
+        if let Err(e) = writer.write_message(since_start.as_micros() as u64, message) {
+            eprintln!("Failed to record message: {}", e);
+        }
+    }
+}
+
+fn connect_dump(
+    transport: &dyn Transport,
+    input_device: &str,
+    recorder: Option<Recorder>,
+    options: &DumpOptions,
+    errors: Arc<ParseErrors>,
+) -> Result<InputConnection, Box<dyn std::error::Error>> {
+    let port = input_device.to_string();
+    let options = options.clone();
+    let mut timekeeper = Timekeeper::new();
+    transport.connect_input(
+        input_device,
+        Box::new(move |stamp, message| {
+            // the backend timestamps of different ports may not share an origin, so the recording
+            // uses the time since the start
+            let since_start = timekeeper.since_start(stamp);
+            let times = timekeeper.fields(&options, stamp, message);
+            let decoded = echo_message(&options, &port, stamp, &times, message, &errors);
+            // undecodable messages would corrupt the track
+            if let (Some(recorder), true) = (&recorder, decoded) {
+                record_message(recorder, since_start, message);
+            }
+        }),
+    )
+}
+
+/// Dumps until `stop` completes, or until the first parse error if they are fatal.
+/// Returns the number of parse errors.
+fn run_dump(
+    transport: &dyn Transport,
+    input_devices: &[String],
+    record: Option<&Path>,
+    options: &DumpOptions,
+    stop: impl Future<Output = ()>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let recorder: Option<Recorder> = match record {
+        Some(path) => Some(Arc::new(Mutex::new(Some(SmfWriter::create(path)?)))),
+        None => None,
+    };
+    let errors = Arc::new(ParseErrors::default());
+    let options = DumpOptions {
+        label_ports: options.label_ports || input_devices.len() > 1,
+        ..options.clone()
+    };
+    let mut connections = Vec::new();
+    for input_device in input_devices {
+        connections.push(connect_dump(
+            transport,
+            input_device,
+            recorder.clone(),
+            &options,
+            errors.clone(),
+        )?);
+    }
+
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        tokio::select! {
+            _ = stop => {},
+            _ = errors.notify.notified(), if options.fatal_errors => {},
+        }
+    });
+    drop(connections);
+
+    if let Some(writer) = recorder.and_then(|recorder| recorder.lock().unwrap().take()) {
+        writer.finish()?;
+    }
+
+    let count = errors.count.load(Ordering::Relaxed);
+    if options.fatal_errors && count > 0 {
+        return Err(Box::from("Stopped after a message that cannot be decoded"));
+    }
+    Ok(count)
+}
+
+/// Dumps the given inputs, or all input ports if `all` is set
+pub fn dump(
+    transport: &dyn Transport,
+    input_devices: &[String],
+    all: bool,
+    record: Option<&Path>,
+    options: &DumpOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_devices = match all {
+        true => transport.input_ports()?,
+        false => input_devices.to_vec(),
+    };
+    if input_devices.is_empty() {
+        return Err(Box::from("No input ports to dump"));
+    }
+
+    let errors = run_dump(
+        transport,
+        &input_devices,
+        record,
+        options,
+        wait_for_sigint(),
+    )?;
+    if errors > 0 {
+        eprintln!("Parse errors: {}", errors);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{
+        connect_dump, format_message, format_wall_clock, run_dump, DumpOptions, Format, TimeField,
+        Timekeeper,
+    };
+    use crate::smf::SmfWriter;
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_dump_record() {
+        let path =
+            std::env::temp_dir().join(format!("test_dump_record_{}.mid", std::process::id()));
+        let recorder = Arc::new(Mutex::new(Some(SmfWriter::create(&path).unwrap())));
+
+        let transport = LoopbackTransport::new();
+        let dump = connect_dump(
+            &transport,
+            "controller",
+            Some(recorder.clone()),
+            &DumpOptions::default(),
+            Arc::default(),
+        )
+        .unwrap();
+
+        let mut controller = transport.connect_output("controller").unwrap();
+        controller.send(&[0x90, 60, 100]).unwrap();
+        controller.send(&[0x80, 60, 0]).unwrap();
+        drop(dump);
+
+        let writer = recorder.lock().unwrap().take().unwrap();
+        writer.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(data.starts_with(b"MThd"));
+        let track_length = u32::from_be_bytes(data[18..22].try_into().unwrap()) as usize;
+        assert_eq!(track_length, data.len() - 22);
+        assert!(data.windows(3).any(|event| event == [0x90, 60, 100]));
+        assert!(data.windows(3).any(|event| event == [0x80, 60, 0]));
+        assert!(data.ends_with(&[0xff, 0x2f, 0x00]));
+    }
+
+    #[test]
+    fn test_format_message() {
+        let note_on = [0x90, 61, 100];
+        assert_eq!(
+            format_message(Format::Compact, "keys", false, 0, &[], &note_on).unwrap(),
+            "ch1 NoteOn C#4 vel 100"
+        );
+        assert_eq!(
+            format_message(Format::Hex, "keys", false, 0, &[], &note_on).unwrap(),
+            "90 3d 64"
+        );
+        assert_eq!(
+            format_message(Format::Json, "keys \"1\"", false, 1500, &[], &note_on).unwrap(),
+            r#"{"timestamp_us": 1500, "port": "keys \"1\"", "bytes": [144, 61, 100], "message": "ch1 NoteOn C#4 vel 100"}"#
+        );
+        assert_eq!(
+            format_message(Format::Compact, "keys", false, 0, &[], &[0xef, 0, 0]).unwrap(),
+            "ch16 PitchBend -8192"
+        );
+        assert_eq!(
+            format_message(Format::Compact, "keys", false, 0, &[], &[0xf0, 1, 2, 0xf7]).unwrap(),
+            "SysEx 4 bytes"
+        );
+        assert_eq!(
+            format_message(Format::Compact, "keys", false, 0, &[], &[0xf8]).unwrap(),
+            "TimingClock"
+        );
+        assert!(format_message(Format::Compact, "keys", false, 0, &[], &[0x90, 60]).is_err());
+        assert!(
+            format_message(Format::Hex, "keys", false, 0, &[], &[0x3c, 0x64])
+                .unwrap_err()
+                .starts_with("3c 64 (cannot decode: ")
+        );
+        assert!(
+            format_message(Format::Json, "keys", false, 0, &[], &[0x3c, 0x64])
+                .unwrap_err()
+                .contains(r#""bytes": [60, 100], "message": null, "error": "#)
+        );
+    }
+
+    #[test]
+    fn test_times() {
+        let options = DumpOptions {
+            format: Format::Hex,
+            time_fields: vec![TimeField::Absolute, TimeField::Delta],
+            per_channel_delta: true,
+            precision: 3,
+            fatal_errors: false,
+            label_ports: false,
+        };
+        let mut timekeeper = Timekeeper::new();
+        let mut line = |stamp, message: &[u8]| {
+            let times = timekeeper.fields(&options, stamp, message);
+            format_message(options.format, "keys", false, stamp, &times, message).unwrap()
+        };
+
+        assert_eq!(line(1_000_000, &[0x90, 60, 100]), "0.000 - 90 3c 64");
+        assert_eq!(line(1_250_000, &[0x91, 60, 100]), "0.250 - 91 3c 64");
+        assert_eq!(line(1_500_000, &[0x80, 60, 0]), "0.500 +0.500 80 3c 00");
+        assert_eq!(line(1_500_000, &[0xf8]), "0.500 - f8");
+
+        let times = [
+            (
+                TimeField::Wall,
+                Some("2023-11-14T22:13:20.500Z".to_string()),
+            ),
+            (TimeField::Delta, None),
+        ];
+        assert_eq!(
+            format_message(Format::Json, "keys", false, 5, &times, &[0xf8]).unwrap(),
+            r#"{"timestamp_us": 5, "wall_clock": "2023-11-14T22:13:20.500Z", "delta_s": null, "port": "keys", "bytes": [248], "message": "TimingClock"}"#
+        );
+    }
+
+    #[test]
+    fn test_format_wall_clock() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(format_wall_clock(time, 3), "2023-11-14T22:13:20.500Z");
+        assert_eq!(format_wall_clock(time, 0), "2023-11-14T22:13:20Z");
+        assert_eq!(
+            format_wall_clock(UNIX_EPOCH + Duration::from_secs(951_782_400), 6),
+            "2000-02-29T00:00:00.000000Z"
+        );
+    }
+
+    #[test]
+    fn test_dump_parse_errors() {
+        let path =
+            std::env::temp_dir().join(format!("test_dump_errors_{}.mid", std::process::id()));
+        let transport = LoopbackTransport::new();
+        let mut controller = transport.connect_output("controller").unwrap();
+        let errors = run_dump(
+            &transport,
+            &["controller".to_string()],
+            Some(&path),
+            &DumpOptions::default(),
+            async move {
+                // running status data bytes without a status byte
+                controller.send(&[0x90, 60, 100]).unwrap();
+                controller.send(&[62, 100]).unwrap();
+                controller.send(&[0xf8]).unwrap();
+                controller.send(&[64, 100]).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await
+            },
+        )
+        .unwrap();
+        assert_eq!(errors, 2);
+
+        // only the decodable messages are recorded
+        let events = crate::smf::read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let messages: Vec<_> = events.into_iter().map(|event| event.message).collect();
+        assert_eq!(messages, [vec![0x90, 60, 100], vec![0xf8]]);
+
+        let mut controller = transport.connect_output("controller").unwrap();
+        let result = run_dump(
+            &transport,
+            &["controller".to_string()],
+            None,
+            &DumpOptions {
+                fatal_errors: true,
+                ..Default::default()
+            },
+            async move {
+                controller.send(&[62, 100]).unwrap();
+                std::future::pending().await
+            },
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_dump_multiple_inputs() {
+        let path =
+            std::env::temp_dir().join(format!("test_dump_multiple_{}.mid", std::process::id()));
+        let transport = LoopbackTransport::new();
+        let mut keys = transport.connect_output("keys").unwrap();
+        let mut pads = transport.connect_output("pads").unwrap();
+
+        let errors = run_dump(
+            &transport,
+            &["keys".to_string(), "pads".to_string()],
+            Some(&path),
+            &DumpOptions::default(),
+            async move {
+                keys.send(&[0x90, 60, 100]).unwrap();
+                pads.send(&[0x99, 36, 100]).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await
+            },
+        )
+        .unwrap();
+        assert_eq!(errors, 0);
+
+        let events = crate::smf::read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let messages: Vec<_> = events.into_iter().map(|event| event.message).collect();
+        assert_eq!(messages, [vec![0x90, 60, 100], vec![0x99, 36, 100]]);
 
-    loop_until_sigint()
+        assert_eq!(
+            format_message(Format::Compact, "pads", true, 0, &[], &[0x99, 36, 100]).unwrap(),
+            "[pads] ch10 NoteOn C2 vel 100"
+        );
+    }
 }
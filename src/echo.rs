@@ -1,8 +1,15 @@
-use crate::utils::{loop_until_sigint, resolve_input_port, resolve_output_port};
-use midir::{Ignore, MidiInput, MidiOutput, MidiOutputConnection};
+use crate::filter::Filter;
+use crate::impair::{ImpairedOutput, Impairments};
+use crate::transport::{InputConnection, OutputConnection, Transport};
+use crate::utils::loop_until_sigint;
 use wmidi::MidiMessage;
 
-fn echo_message(connection: &mut MidiOutputConnection, message: &[u8], print: bool) {
+fn echo_message(
+    connection: &mut Box<dyn OutputConnection>,
+    filter: &Filter,
+    message: &[u8],
+    print: bool,
+) {
     if print {
         match MidiMessage::from_bytes(message) {
             Ok(message) => println!("Received: {:?}", message),
@@ -10,29 +17,128 @@ fn echo_message(connection: &mut MidiOutputConnection, message: &[u8], print: bo
         }
     }
 
+    let Some(message) = filter.apply(message) else {
+        return;
+    };
     connection
-        .send(message)
+        .send(&message)
         .unwrap_or_else(|e| println!("MidiIO error: {}", e));
 }
 
+fn connect_echo(
+    transport: &dyn Transport,
+    input_device: &str,
+    output_device: &str,
+    filter: Filter,
+    impairments: Impairments,
+    print: bool,
+) -> Result<InputConnection, Box<dyn std::error::Error>> {
+    let mut out_connection = transport.connect_output(output_device)?;
+    if impairments.is_active() {
+        out_connection = Box::new(ImpairedOutput::new(out_connection, impairments));
+    }
+
+    transport.connect_input(
+        input_device,
+        Box::new(move |_stamp, message| echo_message(&mut out_connection, &filter, message, print)),
+    )
+}
+
 pub fn echo(
-    input_device: &String,
-    output_device: &String,
+    transport: &dyn Transport,
+    input_device: &str,
+    output_device: &str,
+    filter: Filter,
+    impairments: Impairments,
     print: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut midi_in = MidiInput::new("midi-toolbox input")?;
-    let midi_out = MidiOutput::new("midi-toolbox output")?;
-    midi_in.ignore(Ignore::None);
-
-    let out_port = resolve_output_port(&midi_out, output_device)?;
-    let in_port = resolve_input_port(&midi_in, input_device)?;
-    let mut out_connection = midi_out.connect(&out_port, "Echo output")?;
-
-    let _in_connection = midi_in.connect(
-        &in_port,
-        "MidiToolbox input",
-        move |_stamp, message, _| echo_message(&mut out_connection, message, print),
-        (),
-    );
+    let _in_connection = connect_echo(
+        transport,
+        input_device,
+        output_device,
+        filter,
+        impairments,
+        print,
+    )?;
     loop_until_sigint()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::echo::connect_echo;
+    use crate::filter::{Filter, MessageType};
+    use crate::impair::Impairments;
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_echo() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+
+        let captured = received.clone();
+        let _sink = transport
+            .connect_input(
+                "dut",
+                Box::new(move |_, message| captured.lock().unwrap().push(message.to_vec())),
+            )
+            .unwrap();
+        let _echo = connect_echo(
+            &transport,
+            "controller",
+            "dut",
+            Filter::default(),
+            Impairments::default(),
+            true,
+        )
+        .unwrap();
+
+        let mut controller = transport.connect_output("controller").unwrap();
+        controller.send(&[0x90, 60, 100]).unwrap();
+        controller.send(&[0xb0, 7, 64]).unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            [vec![0x90, 60, 100], vec![0xb0, 7, 64]]
+        );
+    }
+
+    #[test]
+    fn test_echo_filter() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+
+        let captured = received.clone();
+        let _sink = transport
+            .connect_input(
+                "dut",
+                Box::new(move |_, message| captured.lock().unwrap().push(message.to_vec())),
+            )
+            .unwrap();
+        let filter = Filter {
+            drop_types: vec![MessageType::Cc],
+            transpose: -2,
+            channel_map: vec![(0, 1)],
+            ..Default::default()
+        };
+        let _echo = connect_echo(
+            &transport,
+            "controller",
+            "dut",
+            filter,
+            Impairments::default(),
+            false,
+        )
+        .unwrap();
+
+        let mut controller = transport.connect_output("controller").unwrap();
+        controller.send(&[0x90, 60, 100]).unwrap();
+        controller.send(&[0xb0, 7, 64]).unwrap();
+        controller.send(&[0x80, 60, 0]).unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            [vec![0x91, 58, 100], vec![0x81, 58, 0]]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::generator::VelocityCurve;
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum MessageType {
+    /// NoteOn and NoteOff
+    Note,
+    PolyPressure,
+    Cc,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    Sysex,
+    /// System common messages: MTC quarter frame, song position, song select and tune request
+    Common,
+    /// System realtime messages: clock, transport and active sensing
+    Realtime,
+}
+
+impl MessageType {
+    fn of(message: &[u8]) -> Option<MessageType> {
+        match message.first()? {
+            0x80..=0x9f => Some(MessageType::Note),
+            0xa0..=0xaf => Some(MessageType::PolyPressure),
+            0xb0..=0xbf => Some(MessageType::Cc),
+            0xc0..=0xcf => Some(MessageType::ProgramChange),
+            0xd0..=0xdf => Some(MessageType::ChannelPressure),
+            0xe0..=0xef => Some(MessageType::PitchBend),
+            0xf0 | 0xf7 => Some(MessageType::Sysex),
+            0xf1..=0xf6 => Some(MessageType::Common),
+            0xf8..=0xff => Some(MessageType::Realtime),
+            _ => None,
+        }
+    }
+}
+
+/// Drops and rewrites messages on their way from input to output.
+/// The stages are applied in field order, so drops and the controller map see the original channel
+/// and controller numbers.
+#[derive(Clone, Default)]
+pub struct Filter {
+    pub drop_types: Vec<MessageType>,
+    /// Channel indices 0-15
+    pub drop_channels: Vec<u8>,
+    pub controller_map: Vec<(u8, u8)>,
+    /// Semitones added to notes and poly pressure, notes shifted out of range are dropped
+    pub transpose: i8,
+    /// Applied to NoteOn velocities before the scale
+    pub velocity_curve: Option<VelocityCurve>,
+    pub velocity_scale: Option<f64>,
+    /// Channel indices 0-15
+    pub channel_map: Vec<(u8, u8)>,
+}
+
+impl Filter {
+    fn is_identity(&self) -> bool {
+        self.controller_map.is_empty()
+            && self.transpose == 0
+            && self.velocity_curve.is_none()
+            && self.velocity_scale.is_none()
+            && self.channel_map.is_empty()
+    }
+
+    fn map_velocity(&self, velocity: u8) -> u8 {
+        let mut x = velocity as f64 / 127.0;
+        if let Some(curve) = self.velocity_curve {
+            x = curve.apply(x);
+        }
+        x *= self.velocity_scale.unwrap_or(1.0);
+        // a NoteOn must not turn into a NoteOff
+        (x * 127.0).round().clamp(1.0, 127.0) as u8
+    }
+
+    /// The message to forward, `None` if it is dropped
+    pub fn apply<'a>(&self, message: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        // no rule can match a message without a status byte, so it is passed on unchanged
+        let Some(message_type) = MessageType::of(message) else {
+            return Some(Cow::Borrowed(message));
+        };
+        if self.drop_types.contains(&message_type) {
+            return None;
+        }
+        let status = message[0];
+        if status >= 0xf0 {
+            return Some(Cow::Borrowed(message));
+        }
+        if self.drop_channels.contains(&(status & 0x0f)) {
+            return None;
+        }
+        if self.is_identity() {
+            return Some(Cow::Borrowed(message));
+        }
+
+        let mut message = message.to_vec();
+        match (message_type, &mut message[..]) {
+            (MessageType::Cc, [_, controller, _]) => {
+                if let Some((_, to)) = self
+                    .controller_map
+                    .iter()
+                    .find(|(from, _)| from == controller)
+                {
+                    *controller = *to;
+                }
+            }
+            (MessageType::Note | MessageType::PolyPressure, [status, note, velocity]) => {
+                let transposed = *note as i16 + self.transpose as i16;
+                *note = u8::try_from(transposed).ok().filter(|note| *note <= 127)?;
+                if *status & 0xf0 == 0x90 && *velocity > 0 {
+                    *velocity = self.map_velocity(*velocity);
+                }
+            }
+            _ => {}
+        }
+        if let Some((_, to)) = self
+            .channel_map
+            .iter()
+            .find(|(from, _)| *from == message[0] & 0x0f)
+        {
+            message[0] = message[0] & 0xf0 | to;
+        }
+        Some(Cow::Owned(message))
+    }
+}
+
+fn parse_mapping(s: &str, range: RangeInclusive<u8>) -> Result<(u8, u8), String> {
+    let parse = |value: &str| {
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|value| range.contains(value))
+            .ok_or_else(|| format!("Invalid value {:?} in mapping {:?}", value, s))
+    };
+    match s.split_once(':') {
+        Some((from, to)) => Ok((parse(from)?, parse(to)?)),
+        None => Err(format!("Invalid mapping {:?}, expected FROM:TO", s)),
+    }
+}
+
+/// Parses a channel mapping `FROM:TO` of channels 1-16 into channel indices
+pub fn parse_channel_mapping(s: &str) -> Result<(u8, u8), String> {
+    parse_mapping(s, 1..=16).map(|(from, to)| (from - 1, to - 1))
+}
+
+/// Parses a controller mapping `FROM:TO`
+pub fn parse_controller_mapping(s: &str) -> Result<(u8, u8), String> {
+    parse_mapping(s, 0..=127)
+}
+
+/// Parses a velocity scale, a finite factor that is not negative
+pub fn parse_velocity_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale.is_finite() && scale >= 0.0 => Ok(scale),
+        _ => Err(format!("Invalid velocity scale {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{
+        parse_channel_mapping, parse_controller_mapping, parse_velocity_scale, Filter, MessageType,
+    };
+    use crate::generator::VelocityCurve;
+
+    fn apply(filter: &Filter, message: &[u8]) -> Option<Vec<u8>> {
+        filter.apply(message).map(|message| message.to_vec())
+    }
+
+    #[test]
+    fn test_drop() {
+        let filter = Filter {
+            drop_types: vec![MessageType::Realtime, MessageType::PitchBend],
+            drop_channels: vec![9],
+            ..Default::default()
+        };
+        assert_eq!(apply(&filter, &[0xf8]), None);
+        assert_eq!(apply(&filter, &[0xe0, 0, 64]), None);
+        assert_eq!(apply(&filter, &[0x99, 36, 100]), None);
+        assert_eq!(apply(&filter, &[0x90, 36, 100]), Some(vec![0x90, 36, 100]));
+        assert_eq!(apply(&filter, &[0xf0, 1, 0xf7]), Some(vec![0xf0, 1, 0xf7]));
+    }
+
+    #[test]
+    fn test_forward_unclassified() {
+        for filter in [
+            Filter::default(),
+            Filter {
+                drop_types: vec![MessageType::Note, MessageType::Sysex],
+                drop_channels: vec![0],
+                transpose: 12,
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(apply(&filter, &[]), Some(vec![]));
+            assert_eq!(apply(&filter, &[60, 100]), Some(vec![60, 100]));
+        }
+        assert_eq!(
+            apply(&Filter::default(), &[0x90, 60, 100]),
+            Some(vec![0x90, 60, 100])
+        );
+    }
+
+    #[test]
+    fn test_transform() {
+        let filter = Filter {
+            controller_map: vec![(1, 74)],
+            transpose: 12,
+            velocity_scale: Some(0.5),
+            channel_map: vec![(0, 3)],
+            ..Default::default()
+        };
+        assert_eq!(apply(&filter, &[0x90, 60, 100]), Some(vec![0x93, 72, 50]));
+        assert_eq!(apply(&filter, &[0x80, 60, 100]), Some(vec![0x83, 72, 100]));
+        assert_eq!(apply(&filter, &[0x91, 60, 1]), Some(vec![0x91, 72, 1]));
+        assert_eq!(apply(&filter, &[0x90, 60, 0]), Some(vec![0x93, 72, 0]));
+        assert_eq!(apply(&filter, &[0x90, 120, 100]), None);
+        assert_eq!(apply(&filter, &[0xb0, 1, 10]), Some(vec![0xb3, 74, 10]));
+        assert_eq!(apply(&filter, &[0xb0, 7, 10]), Some(vec![0xb3, 7, 10]));
+
+        let filter = Filter {
+            transpose: -12,
+            velocity_curve: Some(VelocityCurve::Exponential),
+            ..Default::default()
+        };
+        assert_eq!(apply(&filter, &[0x90, 5, 100]), None);
+        assert_eq!(apply(&filter, &[0x90, 60, 127]), Some(vec![0x90, 48, 127]));
+        assert_eq!(apply(&filter, &[0x90, 60, 64]), Some(vec![0x90, 48, 32]));
+    }
+
+    #[test]
+    fn test_parse_mapping() {
+        assert_eq!(parse_channel_mapping("1:16"), Ok((0, 15)));
+        assert!(parse_channel_mapping("0:1").is_err());
+        assert!(parse_channel_mapping("1").is_err());
+        assert_eq!(parse_controller_mapping("1:74"), Ok((1, 74)));
+        assert!(parse_controller_mapping("1:128").is_err());
+    }
+
+    #[test]
+    fn test_parse_velocity_scale() {
+        assert_eq!(parse_velocity_scale("0.5"), Ok(0.5));
+        assert_eq!(parse_velocity_scale("0"), Ok(0.0));
+        for invalid in ["NaN", "inf", "-1", "x"] {
+            assert!(parse_velocity_scale(invalid).is_err());
+        }
+    }
+}
@@ -1,85 +1,307 @@
-use crate::generator::Generator;
+use crate::generator::{ChordShape, Generator, GeneratorOptions, MessageKind};
 use crate::loopback_timer::LoopbackTimer;
-use crate::utils::Sender;
-use crate::utils::{resolve_input_port, resolve_output_port};
-use midir::{Ignore, MidiInput, MidiOutput};
+use crate::report;
+use crate::transport::Transport;
+use crate::utils::{wait_for_sigint, Sender};
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::runtime::Builder;
-use tokio::signal;
 use tokio::time::sleep;
 use wmidi::MidiMessage;
 
-pub fn generate_notes(
-    note_duration: Duration,
-    duration_between_notes: Duration,
-    output_device: &String,
-    print: bool,
+fn run_generator(
+    transport: &dyn Transport,
+    options: &GeneratorOptions,
+    output_device: &str,
     loopback_timer: Option<Arc<LoopbackTimer>>,
+    stop: impl Future<Output = ()>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let midi_out = MidiOutput::new("midi-toolbox output")?;
-    let out_port = resolve_output_port(&midi_out, output_device)?;
-    let out_connection = midi_out.connect(&out_port, "Midi Test Tool Generator")?;
+    if options.notes.is_empty() {
+        return Err(Box::from("No notes in the note range and scale"));
+    }
+    if options
+        .max_note_duration
+        .is_some_and(|max| max < options.note_duration)
+    {
+        return Err(Box::from(
+            "Maximum note duration is shorter than the note duration",
+        ));
+    }
+    let step = match options.chord_shape {
+        ChordShape::Random => None,
+        ChordShape::Cluster => Some(1),
+        ChordShape::Stacked => Some(2),
+    };
+    if step.is_some_and(|step| options.chord_size.saturating_sub(1) * step >= options.notes.len()) {
+        return Err(Box::from(
+            "Chords of this size and shape do not fit in the note range and scale",
+        ));
+    }
+    let cc14 = options
+        .message_kinds
+        .iter()
+        .any(|(kind, _)| *kind == MessageKind::Cc14);
+    if cc14 && options.controller >= 32 {
+        return Err(Box::from(
+            "14-bit controller sweeps need a controller below 32",
+        ));
+    }
+    let out_connection = transport.connect_output(output_device)?;
 
     let generator = Generator::new(
-        note_duration,
-        duration_between_notes,
+        options.clone(),
         Sender::Connection(out_connection),
-        print,
         loopback_timer,
     );
 
     let rt = Builder::new_current_thread().enable_all().build()?;
 
     rt.block_on(async {
-        tokio::spawn(async move {
+        let cloned_generator = generator.clone();
+        let task = tokio::spawn(async move {
             loop {
-                generator.schedule_note().await;
-                sleep(generator.note_offset()).await;
+                cloned_generator.schedule().await;
+                sleep(cloned_generator.note_offset()).await;
             }
         });
 
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C signal handler");
+        stop.await;
+        task.abort();
+        generator.release_all().await;
     });
     Ok(())
 }
 
-pub fn generate_and_analyse(
-    note_duration: Duration,
-    duration_between_notes: Duration,
-    input_device: &String,
-    output_device: &String,
-    print: bool,
+pub fn generate_notes(
+    transport: &dyn Transport,
+    options: &GeneratorOptions,
+    output_device: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut midi_in = MidiInput::new("midi-toolbox input")?;
-    midi_in.ignore(Ignore::None);
-    let in_port = resolve_input_port(&midi_in, input_device)?;
+    run_generator(transport, options, output_device, None, wait_for_sigint())
+}
 
-    let analyser = LoopbackTimer::new();
+fn run_loopback_test(
+    transport: &dyn Transport,
+    options: &GeneratorOptions,
+    input_device: &str,
+    output_device: &str,
+    analyser: Arc<LoopbackTimer>,
+    stop: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let captured_analyzer = analyser.clone();
-    let _in_connection = midi_in.connect(
-        &in_port,
-        "MidiToolbox input",
-        move |_stamp, message: &[u8], _| {
+    let _in_connection = transport.connect_input(
+        input_device,
+        Box::new(move |stamp, message: &[u8]| {
             let midi_msg = MidiMessage::from_bytes(message);
             match midi_msg {
-                Ok(midi_msg) => captured_analyzer.process_received_message(&midi_msg),
+                Ok(midi_msg) => captured_analyzer.process_received_message(&midi_msg, stamp),
                 Err(_) => println!("Unhandled midi message: {:?}", midi_msg),
             }
-        },
-        (),
-    );
+        }),
+    )?;
 
-    let _ = generate_notes(
-        note_duration,
-        duration_between_notes,
-        output_device,
-        print,
-        Some(analyser.clone()),
+    run_generator(transport, options, output_device, Some(analyser), stop)
+}
+
+pub struct AnalysisOptions {
+    pub histogram_bucket_width: Duration,
+    /// Messages that are not received back within this time are counted as lost
+    pub loss_timeout: Duration,
+    /// Evaluate the receive timestamps of the midi backend
+    pub backend_timestamps: bool,
+    pub report_json: Option<PathBuf>,
+    pub report_csv: Option<PathBuf>,
+}
+
+pub fn generate_and_analyse(
+    transport: &dyn Transport,
+    options: &GeneratorOptions,
+    input_device: &str,
+    output_device: &str,
+    analysis_options: &AnalysisOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let analyser = LoopbackTimer::new(
+        analysis_options.loss_timeout,
+        analysis_options.backend_timestamps,
     );
+    run_loopback_test(
+        transport,
+        options,
+        input_device,
+        output_device,
+        analyser.clone(),
+        wait_for_sigint(),
+    )?;
 
-    analyser.print_analysis();
+    let analysis = analyser.print_analysis(analysis_options.histogram_bucket_width);
+
+    if let Some(path) = &analysis_options.report_json {
+        report::write_json_file(path, &analyser.samples(), &analysis)?;
+    }
+    if let Some(path) = &analysis_options.report_csv {
+        report::write_csv_file(path, &analyser.samples(), &analysis)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::{run_generator, run_loopback_test};
+    use crate::generator::{ChordShape, GeneratorOptions, MessageKind};
+    use crate::loopback_timer::LoopbackTimer;
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use wmidi::Note;
+
+    #[test]
+    fn test_loopback() {
+        let transport = LoopbackTransport::new();
+        let analyser = LoopbackTimer::new(Duration::from_millis(100), true);
+        run_loopback_test(
+            &transport,
+            &GeneratorOptions {
+                note_duration: Duration::from_millis(20),
+                duration_between_notes: Duration::from_millis(10),
+                unique_probes: true,
+                ..Default::default()
+            },
+            "loop",
+            "loop",
+            analyser.clone(),
+            async { sleep(Duration::from_millis(200)).await },
+        )
+        .unwrap();
+
+        let analysis = analyser.print_analysis(Duration::from_micros(10));
+        let summary = analysis.summary.unwrap();
+        assert_eq!(analysis.driver_summary.unwrap().count, summary.count);
+        assert!(summary.count >= 10);
+        assert!(summary.p50 <= summary.max);
+        assert!(summary.max < Duration::from_millis(100));
+
+        let reliability = analysis.reliability;
+        assert_eq!(reliability.received, summary.count as u64);
+        assert_eq!(
+            reliability.lost + reliability.duplicated + reliability.unexpected,
+            0
+        );
+        assert_eq!(
+            reliability.sent,
+            reliability.received + reliability.in_flight
+        );
+    }
+
+    #[test]
+    fn test_loopback_message_kinds() {
+        let transport = LoopbackTransport::new();
+        let analyser = LoopbackTimer::new(Duration::from_millis(100), false);
+        run_loopback_test(
+            &transport,
+            &GeneratorOptions {
+                note_duration: Duration::from_millis(20),
+                duration_between_notes: Duration::from_millis(2),
+                unique_probes: true,
+                message_kinds: vec![
+                    (MessageKind::Note, 1),
+                    (MessageKind::Cc, 1),
+                    (MessageKind::Cc14, 1),
+                    (MessageKind::PitchBend, 1),
+                    (MessageKind::ChannelPressure, 1),
+                    (MessageKind::PolyPressure, 1),
+                    (MessageKind::ProgramChange, 1),
+                ],
+                ..Default::default()
+            },
+            "loop",
+            "loop",
+            analyser.clone(),
+            async { sleep(Duration::from_millis(200)).await },
+        )
+        .unwrap();
+
+        let samples = analyser.samples();
+        for status in [0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0] {
+            assert!(samples.iter().any(|sample| sample.message[0] == status));
+        }
+        let reliability = analyser.reliability();
+        assert_eq!(
+            reliability.lost + reliability.duplicated + reliability.unexpected,
+            0
+        );
+        assert_eq!(
+            reliability.sent,
+            reliability.received + reliability.in_flight
+        );
+    }
+
+    #[test]
+    fn test_chord_size_validation() {
+        let transport = LoopbackTransport::new();
+        let run = |chord_shape, chord_size| {
+            run_generator(
+                &transport,
+                &GeneratorOptions {
+                    notes: (60..65).map(Note::from_u8_lossy).collect(),
+                    chord_shape,
+                    chord_size,
+                    ..Default::default()
+                },
+                "loop",
+                None,
+                async {},
+            )
+        };
+
+        assert!(run(ChordShape::Cluster, 5).is_ok());
+        assert!(run(ChordShape::Cluster, 6).is_err());
+        assert!(run(ChordShape::Stacked, 3).is_ok());
+        assert!(run(ChordShape::Stacked, 4).is_err());
+        assert!(run(ChordShape::Random, 6).is_ok());
+    }
+
+    #[test]
+    fn test_release_on_stop() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let _input = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_stamp, message: &[u8]| {
+                    captured.lock().unwrap().push(message.to_vec())
+                }),
+            )
+            .unwrap();
+
+        run_generator(
+            &transport,
+            &GeneratorOptions {
+                note_duration: Duration::from_secs(10),
+                duration_between_notes: Duration::from_millis(5),
+                all_notes_off: true,
+                ..Default::default()
+            },
+            "loop",
+            None,
+            async { sleep(Duration::from_millis(50)).await },
+        )
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        let note_ons: Vec<_> = received.iter().filter(|m| m[0] == 0x90).collect();
+        let note_offs: Vec<_> = received.iter().filter(|m| m[0] == 0x80).collect();
+        assert!(!note_ons.is_empty());
+        assert_eq!(note_ons.len(), note_offs.len());
+        for note_on in note_ons {
+            assert!(note_offs.iter().any(|note_off| note_off[1] == note_on[1]));
+        }
+        assert_eq!(
+            received[received.len() - 2..],
+            [[0xb0, 123, 0], [0xb0, 120, 0]]
+        );
+    }
+}
@@ -1,96 +1,573 @@
 use crate::loopback_timer::LoopbackTimer;
 use crate::utils;
 use crate::utils::Sender;
+use clap::ValueEnum;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::prelude::SliceRandom;
-use rand::Rng;
-use std::ops::DerefMut;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::ops::{DerefMut, RangeInclusive};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
-use wmidi::MidiMessage::{NoteOff, NoteOn};
-use wmidi::{Channel, MidiMessage, Note, Velocity};
+use wmidi::MidiMessage::{
+    ChannelPressure, ControlChange, NoteOff, NoteOn, PitchBendChange, PolyphonicKeyPressure,
+    ProgramChange,
+};
+use wmidi::{Channel, MidiMessage, Note, Velocity, U14, U7};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    HarmonicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    WholeTone,
+}
+
+impl Scale {
+    /// Semitones above the root
+    fn intervals(&self) -> &'static [u8] {
+        match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::WholeTone => &[0, 2, 4, 6, 8, 10],
+        }
+    }
+}
+
+/// Notes in `range` that belong to `scale`, only the pitch class of `root` is relevant
+pub fn scale_notes(range: RangeInclusive<u8>, scale: Scale, root: u8) -> Vec<Note> {
+    range
+        .filter(|note| scale.intervals().contains(&((note + 12 - root % 12) % 12)))
+        .map(Note::from_u8_lossy)
+        .collect()
+}
+
+/// Distribution of the velocities within the velocity range
+#[derive(Clone, Copy, ValueEnum)]
+pub enum VelocityCurve {
+    /// No bias, all velocities are equally likely when generating
+    Linear,
+    /// Biased towards low velocities
+    Exponential,
+    /// Biased towards high velocities
+    Logarithmic,
+}
+
+impl VelocityCurve {
+    /// Maps a uniformly distributed `x` in [0, 1) to [0, 1)
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            VelocityCurve::Linear => x,
+            VelocityCurve::Exponential => x * x,
+            VelocityCurve::Logarithmic => x.sqrt(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ChannelMode {
+    /// Cycle through the channels in order
+    Rotate,
+    /// Pick a random channel for each note
+    Random,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, ValueEnum)]
+pub enum MessageKind {
+    /// NoteOn followed by a NoteOff after the note duration
+    Note,
+    /// Sweep of a 7-bit controller
+    Cc,
+    /// Sweep of a 14-bit controller, sent as MSB/LSB pair
+    Cc14,
+    /// Pitch bend ramp
+    PitchBend,
+    /// Random channel pressure
+    ChannelPressure,
+    /// Random polyphonic key pressure, on a held note if there is one
+    PolyPressure,
+    /// Random program change
+    ProgramChange,
+}
+
+/// Parses `KIND` or `KIND:WEIGHT`
+pub fn parse_weighted_kind(s: &str) -> Result<(MessageKind, u32), String> {
+    let (kind, weight) = match s.split_once(':') {
+        Some((kind, weight)) => (
+            kind,
+            weight
+                .parse()
+                .ok()
+                .filter(|weight| *weight > 0)
+                .ok_or_else(|| format!("Invalid weight {:?}", weight))?,
+        ),
+        None => (s, 1),
+    };
+    Ok((MessageKind::from_str(kind, true)?, weight))
+}
+
+/// Position within a sweep that ramps from 0 to 127 and back
+fn sweep_value(step: usize) -> u8 {
+    match (step % 254) as u8 {
+        value @ 0..=127 => value,
+        value => 254 - value,
+    }
+}
+
+/// Scales a 7-bit sweep value to the full 14-bit range
+fn to_14_bit(value: u8) -> u16 {
+    (value as u32 * 16383 / 127) as u16
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ChordShape {
+    /// Random notes of the note pool
+    Random,
+    /// Adjacent notes of the note pool
+    Cluster,
+    /// Every other note of the note pool, i.e. stacked thirds in diatonic scales
+    Stacked,
+}
+
+#[derive(Clone)]
+pub struct GeneratorOptions {
+    pub note_duration: Duration,
+    /// Note durations are randomised between `note_duration` and this duration
+    pub max_note_duration: Option<Duration>,
+    pub duration_between_notes: Duration,
+    pub print: bool,
+    /// Vary velocities so that no two identical messages are in flight at the same time
+    pub unique_probes: bool,
+    /// Seed of the random number generator, the same seed produces the same notes
+    pub seed: u64,
+    /// Notes to choose from
+    pub notes: Vec<Note>,
+    pub velocities: RangeInclusive<u8>,
+    pub velocity_curve: VelocityCurve,
+    pub channels: Vec<Channel>,
+    pub channel_mode: ChannelMode,
+    /// Message kinds with their relative weights
+    pub message_kinds: Vec<(MessageKind, u32)>,
+    /// Controller of CC sweeps, 14-bit sweeps send the LSB on controller + 32
+    pub controller: u8,
+    /// Notes started at the same time
+    pub chord_size: usize,
+    pub chord_shape: ChordShape,
+    /// Highest number of notes held at the same time
+    pub max_polyphony: Option<usize>,
+    /// Send All Notes Off and All Sound Off on all channels when stopping
+    pub all_notes_off: bool,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            note_duration: Duration::from_secs(1),
+            max_note_duration: None,
+            duration_between_notes: Duration::from_millis(500),
+            print: false,
+            unique_probes: false,
+            seed: 0,
+            notes: utils::all_notes().to_vec(),
+            velocities: 1..=127,
+            velocity_curve: VelocityCurve::Linear,
+            channels: vec![Channel::Ch1],
+            channel_mode: ChannelMode::Rotate,
+            message_kinds: vec![(MessageKind::Note, 1)],
+            controller: 1,
+            chord_size: 1,
+            chord_shape: ChordShape::Random,
+            max_polyphony: None,
+            all_notes_off: false,
+        }
+    }
+}
+
+fn note_index(channel: Channel, note: Note) -> usize {
+    channel.index() as usize * 128 + note as usize
+}
 
 pub struct Generator {
-    note_duration: Duration,
-    note_offset: Duration,
-    channel: Channel,
+    options: GeneratorOptions,
+    next_channel: AtomicUsize,
+    kind_weights: WeightedIndex<u32>,
+    sweep_steps: Mutex<HashMap<MessageKind, usize>>,
 
+    /// Indexed by channel * 128 + note
     active_notes: Mutex<fixedbitset::FixedBitSet>,
     sender: Mutex<Sender>,
     loopback_timer: Option<Arc<LoopbackTimer>>,
-    print: bool,
+    rng: Mutex<StdRng>,
 }
 
 impl Generator {
     pub fn new(
-        note_duration: Duration,
-        note_offset: Duration,
+        options: GeneratorOptions,
         sender: Sender,
-        print: bool,
         loopback_timer: Option<Arc<LoopbackTimer>>,
     ) -> Arc<Self> {
         Arc::new(Self {
-            note_duration,
-            note_offset,
-            channel: Channel::Ch1,
-            active_notes: fixedbitset::FixedBitSet::with_capacity(128).into(),
+            next_channel: AtomicUsize::new(0),
+            kind_weights: WeightedIndex::new(
+                options.message_kinds.iter().map(|(_, weight)| *weight),
+            )
+            .expect("No message kinds"),
+            sweep_steps: Default::default(),
+            active_notes: fixedbitset::FixedBitSet::with_capacity(16 * 128).into(),
             sender: sender.into(),
             loopback_timer,
-            print,
+            rng: StdRng::seed_from_u64(options.seed).into(),
+            options,
         })
     }
 
+    /// Sends a message of a randomly chosen kind
+    pub async fn schedule(self: &Arc<Self>) {
+        let kind = {
+            let mut rng = self.rng.lock().await;
+            self.options.message_kinds[self.kind_weights.sample(rng.deref_mut())].0
+        };
+
+        match kind {
+            MessageKind::Note => self.schedule_note().await,
+            kind => {
+                for message in self.make_messages(kind).await {
+                    self.send(message).await;
+                }
+            }
+        }
+    }
+
     pub async fn schedule_note(self: &Arc<Self>) {
-        let note = match self.make_note().await {
-            Some((note, velocity)) => (note, velocity),
-            None => return,
+        let chord = self.make_chord().await;
+        // drawn for the full chord size, so that the random sequence does not depend on the number
+        // of notes that were free
+        let durations = self.random_note_durations().await;
+
+        for (&(channel, note, _), duration) in chord.iter().zip(durations) {
+            let cloned_self = self.clone();
+            tokio::spawn(async move {
+                sleep(duration).await;
+                // the note is no longer active if release_all already sent its NoteOff
+                let mut active_notes = cloned_self.active_notes.lock().await;
+                if active_notes.contains(note_index(channel, note)) {
+                    cloned_self.send_note_off(channel, note).await;
+                    active_notes.set(note_index(channel, note), false);
+                }
+            });
+        }
+
+        for (channel, note, velocity) in chord {
+            self.send(NoteOn(channel, note, velocity)).await;
+        }
+    }
+
+    /// Sends NoteOffs for all held notes, the NoteOffs that are still scheduled must not run
+    /// afterwards
+    pub async fn release_all(&self) {
+        let held_notes: Vec<_> = {
+            let mut active_notes = self.active_notes.lock().await;
+            let held_notes = active_notes.ones().collect();
+            active_notes.clear();
+            held_notes
         };
 
-        let duration = self.note_duration;
-        let cloned_self = self.clone();
-        tokio::spawn(async move {
-            sleep(duration).await;
-            cloned_self
-                .send(NoteOff(
-                    cloned_self.channel,
-                    note.0,
-                    Velocity::from_u8_lossy(0),
-                ))
-                .await;
-            cloned_self
-                .active_notes
-                .lock()
-                .await
-                .set(note.0 as usize, false);
+        for index in held_notes {
+            let channel = Channel::from_index((index / 128) as u8).unwrap();
+            let note = Note::from_u8_lossy((index % 128) as u8);
+            self.send_note_off(channel, note).await;
+        }
+
+        if self.options.all_notes_off {
+            for channel in &self.options.channels {
+                for message in utils::all_notes_off(*channel) {
+                    self.send(message).await;
+                }
+            }
+        }
+    }
+
+    /// One duration for each note of a full chord
+    async fn random_note_durations(&self) -> Vec<Duration> {
+        let mut rng = self.rng.lock().await;
+        (0..self.options.chord_size)
+            .map(|_| match self.options.max_note_duration {
+                Some(max) if max > self.options.note_duration => {
+                    rng.gen_range(self.options.note_duration..=max)
+                }
+                _ => self.options.note_duration,
+            })
+            .collect()
+    }
+
+    fn next_channel(&self, rng: &mut StdRng) -> Channel {
+        let channels = &self.options.channels;
+        match self.options.channel_mode {
+            ChannelMode::Rotate => {
+                channels[self.next_channel.fetch_add(1, Ordering::Relaxed) % channels.len()]
+            }
+            ChannelMode::Random => *channels.choose(rng).unwrap(),
+        }
+    }
+
+    /// Maps a uniformly distributed `x` in [0, 1) to the velocity range, following the curve
+    fn random_velocity(&self, x: f64) -> u8 {
+        let (low, high) = (
+            *self.options.velocities.start(),
+            *self.options.velocities.end(),
+        );
+        let x = self.options.velocity_curve.apply(x);
+        (low as f64 + (high - low + 1) as f64 * x).min(high as f64) as u8
+    }
+
+    fn note_velocity(&self, x: f64, channel: Channel, note: Note) -> Option<Velocity> {
+        let velocity = self.random_velocity(x);
+        if !self.unique_probes() {
+            return Some(Velocity::from_u8_lossy(velocity));
+        }
+
+        // the free velocity closest to the one drawn from the curve
+        let velocities = self.unique_values(self.options.velocities.clone(), |v| {
+            NoteOn(channel, note, v)
         });
+        velocities
+            .iter()
+            .min_by_key(|v| v.abs_diff(velocity))
+            .map(|v| Velocity::from_u8_lossy(*v))
+    }
 
-        self.send(NoteOn(self.channel, note.0, note.1)).await;
+    /// Notes that can be added without exceeding the polyphony limit
+    async fn free_voices(&self) -> usize {
+        match self.options.max_polyphony {
+            Some(max_polyphony) => {
+                max_polyphony.saturating_sub(self.active_notes.lock().await.count_ones(..))
+            }
+            None => usize::MAX,
+        }
     }
 
-    pub async fn make_note(self: &Generator) -> Option<(Note, Velocity)> {
-        let velocity: u8 = rand::thread_rng().gen_range(1..127);
-        let available_notes = self.available_notes().await;
-        if available_notes.is_empty() {
-            return None;
+    /// Up to `chord_size` notes on the same channel, empty if no note can be played.
+    /// Every chord draws the same number of random values from the fixed note pool and skips the
+    /// notes that are held. Which notes are held still affects the chord, but not the random values
+    /// drawn for later chords.
+    pub async fn make_chord(self: &Generator) -> Vec<(Channel, Note, Velocity)> {
+        let mut rng = self.rng.lock().await;
+        let channel = self.next_channel(rng.deref_mut());
+        let notes = &self.options.notes;
+        let mut order: Vec<usize> = (0..notes.len()).collect();
+        order.shuffle(rng.deref_mut());
+        let velocity_draws: Vec<f64> = (0..self.options.chord_size).map(|_| rng.gen()).collect();
+        drop(rng);
+
+        let size = self.options.chord_size.min(self.free_voices().await);
+        let mut active_notes = self.active_notes.lock().await;
+        let is_free = |note: &Note| !active_notes.contains(note_index(channel, *note));
+
+        let mut chord = Vec::new();
+        match self.options.chord_shape {
+            ChordShape::Random => {
+                for note in order.into_iter().map(|i| notes[i]).filter(is_free) {
+                    if chord.len() == size {
+                        break;
+                    }
+                    let x = velocity_draws[chord.len()];
+                    if let Some(velocity) = self.note_velocity(x, channel, note) {
+                        chord.push((channel, note, velocity));
+                    }
+                }
+            }
+            shape => {
+                // chords are built from neighbouring notes of the note pool, so they follow the scale
+                let step = if let ChordShape::Cluster = shape {
+                    1
+                } else {
+                    2
+                };
+
+                for root in order {
+                    let candidate: Vec<_> = (0..size)
+                        .filter_map(|i| notes.get(root + i * step).copied())
+                        .filter(is_free)
+                        .collect();
+                    if candidate.len() < size {
+                        continue;
+                    }
+
+                    let velocities: Option<Vec<_>> = candidate
+                        .iter()
+                        .zip(&velocity_draws)
+                        .map(|(note, x)| self.note_velocity(*x, channel, *note))
+                        .collect();
+                    if let Some(velocities) = velocities {
+                        chord = candidate
+                            .into_iter()
+                            .zip(velocities)
+                            .map(|(note, velocity)| (channel, note, velocity))
+                            .collect();
+                        break;
+                    }
+                }
+            }
         }
 
-        let note = *available_notes.choose(&mut rand::thread_rng()).unwrap();
+        for (channel, note, _) in &chord {
+            active_notes.insert(note_index(*channel, *note));
+        }
+        chord
+    }
 
-        self.active_notes.lock().await.set(note as usize, true);
+    async fn next_sweep_value(&self, kind: MessageKind) -> u8 {
+        let mut sweep_steps = self.sweep_steps.lock().await;
+        let step = sweep_steps.entry(kind).or_default();
+        *step += 1;
+        sweep_value(*step - 1)
+    }
 
-        Some((note, Velocity::from_u8_lossy(velocity)))
+    /// Random value, with unique probes only values for which the message is not in flight
+    fn random_value(
+        &self,
+        rng: &mut StdRng,
+        make_message: impl Fn(U7) -> MidiMessage<'static>,
+    ) -> Option<U7> {
+        let value = if self.unique_probes() {
+            *self.unique_values(0..=127, make_message).choose(rng)?
+        } else {
+            rng.gen_range(0..=127)
+        };
+        Some(U7::from_u8_lossy(value))
     }
 
-    pub async fn available_notes(self: &Generator) -> heapless::Vec<Note, 128> {
-        let active_notes = self.active_notes.lock().await;
-        let available_notes = utils::all_notes()
-            .iter()
-            .filter(|&note| !active_notes.contains(*note as usize))
-            .cloned()
-            .collect();
+    /// Messages of all kinds but notes, empty if no message can be sent without ambiguity
+    async fn make_messages(&self, kind: MessageKind) -> Vec<MidiMessage<'static>> {
+        let mut rng = self.rng.lock().await;
+        let channel = self.next_channel(rng.deref_mut());
+        let controller = self.options.controller;
 
-        available_notes
+        let messages = match kind {
+            MessageKind::Note => unreachable!("Notes are scheduled by schedule_note"),
+            MessageKind::Cc => {
+                let value = U7::from_u8_lossy(self.next_sweep_value(kind).await);
+                vec![ControlChange(
+                    channel,
+                    U7::from_u8_lossy(controller).into(),
+                    value,
+                )]
+            }
+            MessageKind::Cc14 => {
+                let value = to_14_bit(self.next_sweep_value(kind).await);
+                vec![
+                    ControlChange(
+                        channel,
+                        U7::from_u8_lossy(controller).into(),
+                        U7::from_u8_lossy((value >> 7) as u8),
+                    ),
+                    ControlChange(
+                        channel,
+                        U7::from_u8_lossy(controller + 32).into(),
+                        U7::from_u8_lossy((value & 0x7f) as u8),
+                    ),
+                ]
+            }
+            MessageKind::PitchBend => {
+                let value = to_14_bit(self.next_sweep_value(kind).await);
+                vec![PitchBendChange(channel, U14::try_from(value).unwrap())]
+            }
+            MessageKind::ChannelPressure => {
+                match self.random_value(rng.deref_mut(), |v| ChannelPressure(channel, v)) {
+                    Some(value) => vec![ChannelPressure(channel, value)],
+                    None => vec![],
+                }
+            }
+            MessageKind::PolyPressure => {
+                let held_notes: Vec<_> = self
+                    .active_notes
+                    .lock()
+                    .await
+                    .ones()
+                    .filter(|index| index / 128 == channel.index() as usize)
+                    .map(|index| Note::from_u8_lossy((index % 128) as u8))
+                    .collect();
+                // a single draw whether or not notes are held, to keep the random sequence in step
+                let x: f64 = rng.gen();
+                let note = match held_notes.is_empty() {
+                    true => self.options.notes[(x * self.options.notes.len() as f64) as usize],
+                    false => held_notes[(x * held_notes.len() as f64) as usize],
+                };
+                match self
+                    .random_value(rng.deref_mut(), |v| PolyphonicKeyPressure(channel, note, v))
+                {
+                    Some(value) => vec![PolyphonicKeyPressure(channel, note, value)],
+                    None => vec![],
+                }
+            }
+            MessageKind::ProgramChange => {
+                match self.random_value(rng.deref_mut(), |v| ProgramChange(channel, v)) {
+                    Some(value) => vec![ProgramChange(channel, value)],
+                    None => vec![],
+                }
+            }
+        };
+
+        // sweeps repeat their values, identical messages must not be in flight at the same time
+        let timer = self.loopback_timer.as_ref();
+        if self.unique_probes()
+            && messages
+                .iter()
+                .any(|message| timer.unwrap().is_pending(message))
+        {
+            return vec![];
+        }
+        messages
+    }
+
+    fn unique_probes(&self) -> bool {
+        self.options.unique_probes && self.loopback_timer.is_some()
+    }
+
+    /// Values in `range` for which the message is not currently in flight
+    fn unique_values(
+        &self,
+        range: RangeInclusive<u8>,
+        make_message: impl Fn(U7) -> MidiMessage<'static>,
+    ) -> Vec<u8> {
+        let timer = self.loopback_timer.as_ref().unwrap();
+        range
+            .filter(|v| !timer.is_pending(&make_message(Velocity::from_u8_lossy(*v))))
+            .collect()
+    }
+
+    /// With unique probes the release velocity tags the NoteOff. The tag is reserved under the
+    /// timer lock; if all tags are in flight, the oldest probe is given up.
+    async fn send_note_off(self: &Generator, channel: Channel, note: Note) {
+        let mut sender = self.sender.lock().await;
+
+        let msg = match &self.loopback_timer {
+            Some(timer) if self.unique_probes() => {
+                let candidates: Vec<_> = (0..=127)
+                    .map(|v| NoteOff(channel, note, Velocity::from_u8_lossy(v)))
+                    .collect();
+                timer.record_unique(&candidates).unwrap()
+            }
+            timer => {
+                let msg = NoteOff(channel, note, Velocity::MIN);
+                if let Some(timer) = timer {
+                    timer.record_message(&msg);
+                }
+                msg
+            }
+        };
+        self.transmit(sender.deref_mut(), &msg);
     }
 
     async fn send(self: &Generator, msg: MidiMessage<'_>) {
@@ -99,60 +576,424 @@ impl Generator {
         if let Some(timer) = self.loopback_timer.clone() {
             timer.record_message(&msg);
         }
+        self.transmit(sender.deref_mut(), &msg);
+    }
 
-        if self.print {
+    fn transmit(&self, sender: &mut Sender, msg: &MidiMessage) {
+        if self.options.print {
             println!("Sending midi message: {:?}", msg);
         }
 
-        match sender.deref_mut() {
-            Sender::Function(f) => f(&msg),
-            Sender::Connection(c) => {
-                let mut data = [0u8; 16];
-
-                msg.copy_to_slice(&mut data).unwrap();
-                c.send(&data).expect("Sending failed")
-            }
+        match sender {
+            Sender::Function(f) => f(msg),
+            Sender::Connection(c) => c.send(&utils::to_vec(msg)).expect("Sending failed"),
         }
     }
 
     pub fn note_offset(&self) -> Duration {
-        self.note_offset
+        self.options.duration_between_notes
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::generator::Generator;
+    use crate::generator::{
+        note_index, parse_weighted_kind, scale_notes, sweep_value, to_14_bit, ChannelMode,
+        ChordShape, Generator, GeneratorOptions, MessageKind, Scale, VelocityCurve,
+    };
+    use crate::loopback_timer::LoopbackTimer;
+    use crate::transport::{LoopbackTransport, Transport};
+    use crate::utils;
     use crate::utils::Sender;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
+    use wmidi::Channel::{self, Ch1, Ch10, Ch2};
+    use wmidi::MidiMessage;
+    use wmidi::MidiMessage::{NoteOff, NoteOn};
+    use wmidi::{Note, Velocity};
+
+    fn options(unique_probes: bool) -> GeneratorOptions {
+        GeneratorOptions {
+            note_duration: Duration::from_millis(100),
+            duration_between_notes: Duration::from_millis(100),
+            unique_probes,
+            ..Default::default()
+        }
+    }
+
+    async fn available_notes(gen: &Generator, channel: Channel) -> Vec<Note> {
+        let active_notes = gen.active_notes.lock().await;
+        gen.options
+            .notes
+            .iter()
+            .filter(|&note| !active_notes.contains(note_index(channel, *note)))
+            .cloned()
+            .collect()
+    }
 
     #[tokio::test]
-    async fn test_make_note() {
+    async fn test_make_chord() {
         let gen = Generator::new(
-            Duration::from_millis(100),
-            Duration::from_millis(100),
+            options(false),
             Sender::Function(|msg| println!("Sending {:?}", msg)),
-            false,
             None,
         );
-        assert_eq!(gen.available_notes().await.len(), 128);
-        let note = gen.make_note().await.unwrap().0;
-        assert!(!gen.available_notes().await.contains(&note));
-        assert!(gen.active_notes.lock().await.contains(note as usize));
+        assert_eq!(available_notes(&gen, Ch1).await.len(), 128);
+        let (channel, note, _) = gen.make_chord().await[0];
+        assert_eq!(channel, Ch1);
+        assert!(!available_notes(&gen, Ch1).await.contains(&note));
+        assert!(available_notes(&gen, Ch2).await.contains(&note));
+        assert!(gen
+            .active_notes
+            .lock()
+            .await
+            .contains(note_index(Ch1, note)));
     }
 
     #[tokio::test]
     async fn test_schedule_note() {
         let gen = Generator::new(
-            Duration::from_millis(100),
-            Duration::from_millis(100),
+            options(false),
             Sender::Function(|msg| println!("Sending {:?}", msg)),
-            false,
             None,
         );
-        assert_eq!(gen.available_notes().await.len(), 128);
+        assert_eq!(available_notes(&gen, Ch1).await.len(), 128);
         gen.schedule_note().await;
         tokio::time::sleep(Duration::from_millis(1000)).await;
-        assert_eq!(gen.available_notes().await.len(), 128);
+        assert_eq!(available_notes(&gen, Ch1).await.len(), 128);
+    }
+
+    #[tokio::test]
+    async fn test_unique_probes() {
+        let timer = LoopbackTimer::new(Duration::from_secs(1), false);
+        let gen = Generator::new(
+            options(true),
+            Sender::Function(|msg| println!("Sending {:?}", msg)),
+            Some(timer.clone()),
+        );
+
+        timer.record_message(&NoteOn(Ch1, Note::A0, Velocity::from_u8_lossy(10)));
+        let velocities = gen.unique_values(1..=126, |v| NoteOn(Ch1, Note::A0, v));
+        assert_eq!(velocities.len(), 125);
+        assert!(!velocities.contains(&10));
+
+        for v in 1..128 {
+            timer.record_message(&NoteOff(Ch1, Note::A0, Velocity::from_u8_lossy(v)));
+        }
+        gen.send_note_off(Ch1, Note::A0).await;
+        assert!(timer.is_pending(&NoteOff(Ch1, Note::A0, Velocity::MIN)));
+        assert_eq!(timer.reliability().lost, 0);
+
+        // all tags in flight, the oldest one is reused
+        gen.send_note_off(Ch1, Note::A0).await;
+        assert_eq!(timer.reliability().lost, 1);
+        assert_eq!(timer.reliability().sent, 130);
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let notes = |seed| async move {
+            let gen = Generator::new(
+                GeneratorOptions {
+                    seed,
+                    ..options(false)
+                },
+                Sender::Function(|_| {}),
+                None,
+            );
+            let mut notes = Vec::new();
+            for _ in 0..10 {
+                notes.extend(gen.make_chord().await);
+            }
+            notes
+        };
+
+        assert_eq!(notes(37).await, notes(37).await);
+        assert_ne!(notes(37).await, notes(38).await);
+    }
+
+    #[tokio::test]
+    async fn test_seed_with_held_notes() {
+        let chords = |held: bool| async move {
+            let gen = Generator::new(options(false), Sender::Function(|_| {}), None);
+            if held {
+                // as if a NoteOff was late
+                gen.active_notes
+                    .lock()
+                    .await
+                    .insert(note_index(Ch1, Note::C4));
+            }
+            gen.make_chord().await;
+            gen.active_notes.lock().await.clear();
+
+            let mut notes = Vec::new();
+            for _ in 0..10 {
+                notes.extend(gen.make_chord().await);
+            }
+            notes
+        };
+
+        assert_eq!(chords(false).await, chords(true).await);
+    }
+
+    #[test]
+    fn test_scale_notes() {
+        let notes: Vec<_> = scale_notes(60..=72, Scale::Major, 60)
+            .into_iter()
+            .map(|note| note as u8)
+            .collect();
+        assert_eq!(notes, [60, 62, 64, 65, 67, 69, 71, 72]);
+
+        let notes: Vec<_> = scale_notes(0..=127, Scale::MinorPentatonic, 69)
+            .into_iter()
+            .map(|note| note as u8)
+            .collect();
+        assert_eq!(notes[..5], [0, 2, 4, 7, 9]);
+        assert_eq!(scale_notes(0..=127, Scale::Chromatic, 0).len(), 128);
+    }
+
+    #[tokio::test]
+    async fn test_channels_and_ranges() {
+        let notes = scale_notes(36..=48, Scale::WholeTone, 0);
+        let gen = Generator::new(
+            GeneratorOptions {
+                notes: notes.clone(),
+                velocities: 100..=110,
+                velocity_curve: VelocityCurve::Exponential,
+                channels: vec![Ch2, Ch10],
+                channel_mode: ChannelMode::Rotate,
+                ..options(false)
+            },
+            Sender::Function(|_| {}),
+            None,
+        );
+
+        for i in 0..12 {
+            let (channel, note, velocity) = gen.make_chord().await[0];
+            assert_eq!(channel, if i % 2 == 0 { Ch2 } else { Ch10 });
+            assert!(notes.contains(&note));
+            assert!((100..=110).contains(&u8::from(velocity)));
+        }
+        // all 7 notes are held on both channels afterwards
+        assert!(!gen.make_chord().await.is_empty());
+        assert!(!gen.make_chord().await.is_empty());
+        assert!(gen.make_chord().await.is_empty());
+    }
+
+    #[test]
+    fn test_parse_weighted_kind() {
+        assert_eq!(parse_weighted_kind("note"), Ok((MessageKind::Note, 1)));
+        assert_eq!(
+            parse_weighted_kind("pitch-bend:3"),
+            Ok((MessageKind::PitchBend, 3))
+        );
+        assert!(parse_weighted_kind("cc:0").is_err());
+        assert!(parse_weighted_kind("sysex").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_make_messages() {
+        let gen = Generator::new(
+            GeneratorOptions {
+                controller: 7,
+                ..options(false)
+            },
+            Sender::Function(|_| {}),
+            None,
+        );
+
+        let bytes = |messages: Vec<MidiMessage>| -> Vec<Vec<u8>> {
+            messages
+                .iter()
+                .map(|message| utils::to_vec(message).to_vec())
+                .collect()
+        };
+        for value in [0, 1, 2] {
+            let messages = gen.make_messages(MessageKind::Cc).await;
+            assert_eq!(bytes(messages), [vec![0xb0, 7, value]]);
+        }
+        assert_eq!(
+            bytes(gen.make_messages(MessageKind::Cc14).await),
+            [vec![0xb0, 7, 0], vec![0xb0, 39, 0]]
+        );
+        assert_eq!(
+            bytes(gen.make_messages(MessageKind::Cc14).await),
+            [vec![0xb0, 7, 1], vec![0xb0, 39, 1]]
+        );
+        gen.make_messages(MessageKind::PitchBend).await;
+        assert_eq!(
+            bytes(gen.make_messages(MessageKind::PitchBend).await),
+            [vec![0xe0, 1, 1]]
+        );
+        let messages = bytes(gen.make_messages(MessageKind::ProgramChange).await);
+        assert_eq!(messages[0][0], 0xc0);
+    }
+
+    #[test]
+    fn test_sweep_value() {
+        let values: Vec<_> = (125..131).map(sweep_value).collect();
+        assert_eq!(values, [125, 126, 127, 126, 125, 124]);
+        assert_eq!(sweep_value(254), 0);
+        assert_eq!(to_14_bit(127), 16383);
+    }
+
+    #[tokio::test]
+    async fn test_chords() {
+        let chord = |shape| async move {
+            let gen = Generator::new(
+                GeneratorOptions {
+                    notes: scale_notes(60..=72, Scale::Major, 0),
+                    chord_size: 3,
+                    chord_shape: shape,
+                    max_polyphony: Some(4),
+                    ..options(false)
+                },
+                Sender::Function(|_| {}),
+                None,
+            );
+            let notes: Vec<_> = gen
+                .make_chord()
+                .await
+                .iter()
+                .map(|(_, note, _)| *note as u8)
+                .collect();
+            // only one more note fits into the polyphony limit
+            assert_eq!(gen.make_chord().await.len(), 1);
+            assert!(gen.make_chord().await.is_empty());
+            notes
+        };
+
+        let scale = [60, 62, 64, 65, 67, 69, 71, 72];
+        let cluster = chord(ChordShape::Cluster).await;
+        let root = scale.iter().position(|note| *note == cluster[0]).unwrap();
+        assert_eq!(cluster, scale[root..root + 3]);
+
+        let stacked = chord(ChordShape::Stacked).await;
+        let root = scale.iter().position(|note| *note == stacked[0]).unwrap();
+        assert_eq!(stacked, [scale[root], scale[root + 2], scale[root + 4]]);
+
+        let mut random = chord(ChordShape::Random).await;
+        random.sort();
+        random.dedup();
+        assert_eq!(random.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_random_note_duration() {
+        let gen = Generator::new(
+            GeneratorOptions {
+                note_duration: Duration::from_millis(10),
+                max_note_duration: Some(Duration::from_millis(20)),
+                ..options(false)
+            },
+            Sender::Function(|_| {}),
+            None,
+        );
+
+        let mut durations = Vec::new();
+        for _ in 0..20 {
+            durations.extend(gen.random_note_durations().await);
+        }
+        assert!(durations
+            .iter()
+            .all(|d| (Duration::from_millis(10)..=Duration::from_millis(20)).contains(d)));
+        assert!(durations.iter().any(|d| *d != durations[0]));
+    }
+
+    #[tokio::test]
+    async fn test_release_all() {
+        let gen = Generator::new(
+            GeneratorOptions {
+                chord_size: 3,
+                channels: vec![Ch1, Ch2],
+                all_notes_off: true,
+                ..options(false)
+            },
+            Sender::Function(|_| {}),
+            None,
+        );
+        gen.make_chord().await;
+        gen.make_chord().await;
+        assert_eq!(gen.active_notes.lock().await.count_ones(..), 6);
+
+        gen.release_all().await;
+        assert_eq!(gen.active_notes.lock().await.count_ones(..), 0);
+        assert_eq!(available_notes(&gen, Ch2).await.len(), 128);
+    }
+
+    #[tokio::test]
+    async fn test_release_all_with_pending_note_offs() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let _input = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_, message: &[u8]| captured.lock().unwrap().push(message.to_vec())),
+            )
+            .unwrap();
+        let gen = Generator::new(
+            GeneratorOptions {
+                chord_size: 3,
+                note_duration: Duration::from_millis(20),
+                ..options(false)
+            },
+            Sender::Connection(transport.connect_output("loop").unwrap()),
+            None,
+        );
+
+        gen.schedule_note().await;
+        gen.release_all().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let received = received.lock().unwrap();
+        let count = |status| received.iter().filter(|m| m[0] == status).count();
+        assert_eq!(count(0x90), 3);
+        assert_eq!(count(0x80), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_seed_with_overlapping_notes() {
+        let run = || async {
+            let transport = LoopbackTransport::new();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let captured = received.clone();
+            let _input = transport
+                .connect_input(
+                    "loop",
+                    Box::new(move |_, message: &[u8]| {
+                        captured.lock().unwrap().push(message.to_vec())
+                    }),
+                )
+                .unwrap();
+            let gen = Generator::new(
+                GeneratorOptions {
+                    notes: (60..64).map(Note::from_u8_lossy).collect(),
+                    chord_size: 2,
+                    note_duration: Duration::from_millis(25),
+                    duration_between_notes: Duration::from_millis(10),
+                    seed: 5,
+                    ..Default::default()
+                },
+                Sender::Connection(transport.connect_output("loop").unwrap()),
+                None,
+            );
+
+            let schedule = tokio::spawn(async move {
+                loop {
+                    gen.schedule().await;
+                    tokio::time::sleep(gen.note_offset()).await;
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            schedule.abort();
+
+            let received = received.lock().unwrap().clone();
+            received
+        };
+
+        let first = run().await;
+        assert!(first.iter().filter(|m| m[0] == 0x90).count() >= 20);
+        assert_eq!(first, run().await);
     }
 }
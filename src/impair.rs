@@ -0,0 +1,267 @@
+use crate::transport::OutputConnection;
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum JitterDistribution {
+    /// Uniform between zero and the jitter
+    #[default]
+    Uniform,
+    /// Absolute value of a normal distribution with the jitter as standard deviation
+    Normal,
+    /// Exponential with the jitter as mean, a long tail of late messages
+    Exponential,
+}
+
+impl JitterDistribution {
+    fn sample(&self, rng: &mut StdRng, jitter: Duration) -> Duration {
+        // in (0, 1], so that the logarithms below are finite
+        let u = 1.0 - rng.gen::<f64>();
+        let factor = match self {
+            JitterDistribution::Uniform => u,
+            JitterDistribution::Normal => {
+                (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * rng.gen::<f64>()).cos()
+            }
+            JitterDistribution::Exponential => -u.ln(),
+        };
+        jitter.mul_f64(factor.abs())
+    }
+}
+
+/// Misbehaviour of a simulated link
+#[derive(Clone, Default)]
+pub struct Impairments {
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub jitter_distribution: JitterDistribution,
+    /// Probability in [0, 1] that a message is lost
+    pub drop_probability: f64,
+    /// Probability in [0, 1] that a message is sent twice
+    pub duplicate_probability: f64,
+    /// Let the jitter overtake messages, otherwise messages keep their order
+    pub reorder: bool,
+    pub seed: u64,
+}
+
+impl Impairments {
+    pub fn is_active(&self) -> bool {
+        !self.delay.is_zero()
+            || !self.jitter.is_zero()
+            || self.drop_probability > 0.0
+            || self.duplicate_probability > 0.0
+    }
+}
+
+type Scheduled = Reverse<(Instant, u64, Vec<u8>)>;
+
+/// Sends the queued messages at their deadlines, messages with the same deadline in arrival order.
+/// Returns once the sender is dropped and the queue is empty.
+fn run_queue(mut output: Box<dyn OutputConnection>, receiver: mpsc::Receiver<(Instant, Vec<u8>)>) {
+    let mut queue: BinaryHeap<Scheduled> = BinaryHeap::new();
+    let mut sequence = 0u64;
+    loop {
+        let received = match queue.peek() {
+            Some(Reverse((deadline, ..))) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((deadline, message)) => {
+                queue.push(Reverse((deadline, sequence, message)));
+                sequence += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => match queue.peek() {
+                Some(Reverse((deadline, ..))) => {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => return,
+            },
+        }
+
+        while let Some(Reverse((deadline, ..))) = queue.peek() {
+            if *deadline > Instant::now() {
+                break;
+            }
+            let Reverse((_, _, message)) = queue.pop().unwrap();
+            output
+                .send(&message)
+                .unwrap_or_else(|e| println!("MidiIO error: {}", e));
+        }
+    }
+}
+
+/// Output that drops, duplicates and delays messages before passing them on from a queue thread
+pub struct ImpairedOutput {
+    impairments: Impairments,
+    rng: StdRng,
+    /// Deadline of the last queued message, later messages are not scheduled before it unless
+    /// reordering is enabled
+    last_deadline: Instant,
+    sender: Sender<(Instant, Vec<u8>)>,
+}
+
+impl ImpairedOutput {
+    pub fn new(output: Box<dyn OutputConnection>, impairments: Impairments) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run_queue(output, receiver));
+        ImpairedOutput {
+            rng: StdRng::seed_from_u64(impairments.seed),
+            impairments,
+            last_deadline: Instant::now(),
+            sender,
+        }
+    }
+
+    fn deadline(&mut self, now: Instant) -> Instant {
+        let jitter = self
+            .impairments
+            .jitter_distribution
+            .sample(&mut self.rng, self.impairments.jitter);
+        let deadline = now + self.impairments.delay + jitter;
+        if self.impairments.reorder {
+            return deadline;
+        }
+        self.last_deadline = self.last_deadline.max(deadline);
+        self.last_deadline
+    }
+}
+
+impl OutputConnection for ImpairedOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        if self.rng.gen_bool(self.impairments.drop_probability) {
+            return Ok(());
+        }
+        let copies = match self.rng.gen_bool(self.impairments.duplicate_probability) {
+            true => 2,
+            false => 1,
+        };
+        let now = Instant::now();
+        for _ in 0..copies {
+            let deadline = self.deadline(now);
+            self.sender.send((deadline, message.to_vec()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::impair::{ImpairedOutput, Impairments, JitterDistribution};
+    use crate::transport::{LoopbackTransport, OutputConnection, Transport};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    fn send_impaired(impairments: Impairments, count: u8) -> Vec<(Duration, u8)> {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let start = Instant::now();
+        let _input = transport
+            .connect_input(
+                "dut",
+                Box::new(move |_, message: &[u8]| {
+                    captured.lock().unwrap().push((start.elapsed(), message[1]))
+                }),
+            )
+            .unwrap();
+
+        let mut output = ImpairedOutput::new(transport.connect_output("dut").unwrap(), impairments);
+        for note in 0..count {
+            output.send(&[0x90, note, 100]).unwrap();
+        }
+        drop(output);
+        sleep(Duration::from_millis(100));
+
+        let received = received.lock().unwrap().clone();
+        received
+    }
+
+    #[test]
+    fn test_jitter_distribution() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let jitter = Duration::from_millis(1);
+        for distribution in [
+            JitterDistribution::Uniform,
+            JitterDistribution::Normal,
+            JitterDistribution::Exponential,
+        ] {
+            let samples: Vec<_> = (0..1000)
+                .map(|_| distribution.sample(&mut rng, jitter))
+                .collect();
+            let mean = samples.iter().sum::<Duration>() / 1000;
+            assert!(mean > Duration::from_micros(400) && mean < Duration::from_micros(1200));
+        }
+        assert!((0..1000).all(|_| JitterDistribution::Uniform.sample(&mut rng, jitter) <= jitter));
+    }
+
+    #[test]
+    fn test_delay_keeps_order() {
+        let received = send_impaired(
+            Impairments {
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(5),
+                seed: 1,
+                ..Default::default()
+            },
+            50,
+        );
+
+        let notes: Vec<_> = received.iter().map(|(_, note)| *note).collect();
+        assert_eq!(notes, (0..50).collect::<Vec<_>>());
+        assert!(received[0].0 >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let received = send_impaired(
+            Impairments {
+                jitter: Duration::from_millis(10),
+                reorder: true,
+                seed: 1,
+                ..Default::default()
+            },
+            50,
+        );
+
+        let mut notes: Vec<_> = received.iter().map(|(_, note)| *note).collect();
+        assert_ne!(notes, (0..50).collect::<Vec<_>>());
+        notes.sort();
+        assert_eq!(notes, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drop_and_duplicate() {
+        let impairments = Impairments {
+            drop_probability: 0.2,
+            duplicate_probability: 0.2,
+            seed: 7,
+            ..Default::default()
+        };
+        let received = send_impaired(impairments.clone(), 100);
+
+        let notes: Vec<_> = received.iter().map(|(_, note)| *note).collect();
+        let dropped = (0..100).filter(|note| !notes.contains(note)).count();
+        let duplicated = notes.windows(2).filter(|pair| pair[0] == pair[1]).count();
+        assert!(dropped > 5 && dropped < 40);
+        assert!(duplicated > 5 && duplicated < 40);
+
+        // the same seed gives the same result
+        let repeated: Vec<_> = send_impaired(impairments, 100)
+            .iter()
+            .map(|(_, note)| *note)
+            .collect();
+        assert_eq!(notes, repeated);
+    }
+}
@@ -1,18 +1,14 @@
-use midir::{Ignore, MidiInput, MidiOutput};
-
-pub fn list_devices() -> Result<(), Box<dyn std::error::Error>> {
-    let mut midi_in = MidiInput::new("Midi Test Tool")?;
-    midi_in.ignore(Ignore::None);
-    let midi_out = MidiOutput::new("Midi Test Tool")?;
+use crate::transport::Transport;
 
+pub fn list_devices(transport: &dyn Transport) -> Result<(), Box<dyn std::error::Error>> {
     println!("Available input ports:");
-    for (i, p) in midi_in.ports().iter().enumerate() {
-        println!("{}: {}", i, midi_in.port_name(p)?);
+    for (i, p) in transport.input_ports()?.iter().enumerate() {
+        println!("{}: {}", i, p);
     }
 
     println!("\nAvailable output ports:");
-    for (i, p) in midi_out.ports().iter().enumerate() {
-        println!("{}: {}", i, midi_out.port_name(p)?);
+    for (i, p) in transport.output_ports()?.iter().enumerate() {
+        println!("{}: {}", i, p);
     }
 
     Ok(())
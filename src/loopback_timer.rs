@@ -1,61 +1,304 @@
-use crate::analysis;
+use crate::analysis::{Histogram, Summary};
 use crate::utils::to_vec;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+
+/// A message that was sent and received back
+#[derive(Clone)]
+pub struct Sample {
+    pub send_time: SystemTime,
+    pub receive_time: SystemTime,
+    pub message: heapless::Vec<u8, 8>,
+    pub latency: Duration,
+    /// Latency derived from the backend receive timestamp, only with backend timestamps
+    pub driver_latency: Option<Duration>,
+}
+
+/// Delivery statistics of the loopback link
+#[derive(Clone, Default)]
+pub struct Reliability {
+    pub sent: u64,
+    pub received: u64,
+    /// Not received within the timeout, or sent again before the previous copy was received
+    pub lost: u64,
+    pub duplicated: u64,
+    /// Received before a message that was sent earlier
+    pub out_of_order: u64,
+    /// Received after the timeout
+    pub late: u64,
+    /// Received without being sent
+    pub unexpected: u64,
+    /// Still pending, but younger than the timeout
+    pub in_flight: u64,
+}
+
+impl fmt::Display for Reliability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sent: {}, Received: {}, Lost: {}, Duplicated: {}, Out of order: {}, Late: {}, \
+             Unexpected: {}, In flight: {}",
+            self.sent,
+            self.received,
+            self.lost,
+            self.duplicated,
+            self.out_of_order,
+            self.late,
+            self.unexpected,
+            self.in_flight
+        )
+    }
+}
+
+pub struct Analysis {
+    pub summary: Option<Summary>,
+    /// Latency until the backend received the message, only with backend timestamps
+    pub driver_summary: Option<Summary>,
+    /// Delay between the backend receive timestamp and the callback, only with backend timestamps
+    pub dispatch_summary: Option<Summary>,
+    pub reliability: Reliability,
+}
+
+struct PendingMessage {
+    sequence: u64,
+    send_time: SystemTime,
+    send_instant: Instant,
+}
+
+struct BackendTiming {
+    /// Send time relative to `LoopbackTimerImpl::start`
+    send: Duration,
+    /// Backend receive timestamp in microseconds
+    stamp: u64,
+}
 
 struct LoopbackTimerImpl {
-    pending_notes: BTreeMap<heapless::Vec<u8, 8>, SystemTime>,
+    pending_notes: BTreeMap<heapless::Vec<u8, 8>, PendingMessage>,
+    delivered: BTreeSet<heapless::Vec<u8, 8>>,
+    last_received_sequence: Option<u64>,
+    loss_timeout: Duration,
+    reliability: Reliability,
 
-    latencies: Vec<Duration>,
+    samples: Vec<Sample>,
+
+    start: Instant,
+    backend_timestamps: bool,
+    /// Smallest observed difference between callback time and backend timestamp (in nanoseconds),
+    /// i.e. the offset of the backend clock to `start` plus the minimal dispatch delay
+    clock_offset: Option<i128>,
+    backend_timings: Vec<BackendTiming>,
 }
 
 impl LoopbackTimerImpl {
-    fn new() -> Self {
+    fn new(loss_timeout: Duration, backend_timestamps: bool) -> Self {
         let mut ret = Self {
             pending_notes: Default::default(),
-            latencies: Default::default(),
+            delivered: Default::default(),
+            last_received_sequence: None,
+            loss_timeout,
+            reliability: Default::default(),
+            samples: Default::default(),
+            start: Instant::now(),
+            backend_timestamps,
+            clock_offset: None,
+            backend_timings: Default::default(),
         };
-        ret.latencies.reserve(1000000000);
+        ret.samples.reserve(1 << 20);
+        if backend_timestamps {
+            ret.backend_timings.reserve(1 << 20);
+        }
         ret
     }
 
     fn record_message(&mut self, midi_message: &wmidi::MidiMessage) {
         let now = SystemTime::now();
-        self.pending_notes.insert(to_vec(midi_message), now);
+        let bytes = to_vec(midi_message);
+
+        let pending = PendingMessage {
+            sequence: self.reliability.sent,
+            send_time: now,
+            send_instant: Instant::now(),
+        };
+        self.reliability.sent += 1;
+
+        self.delivered.remove(&bytes);
+        if self.pending_notes.insert(bytes, pending).is_some() {
+            self.reliability.lost += 1;
+        }
     }
 
-    fn process_received_message(&mut self, midi_message: &wmidi::MidiMessage) {
+    /// Records the first candidate that is not in flight. If all of them are, the one sent longest
+    /// ago is sent again, which counts its earlier probe as lost.
+    fn record_unique<'a>(
+        &mut self,
+        candidates: &[wmidi::MidiMessage<'a>],
+    ) -> Option<wmidi::MidiMessage<'a>> {
+        let sequence = |message: &wmidi::MidiMessage| {
+            self.pending_notes
+                .get(&to_vec(message))
+                .map(|pending| pending.sequence)
+        };
+        let message = candidates
+            .iter()
+            .find(|message| sequence(message).is_none())
+            .or_else(|| candidates.iter().min_by_key(|message| sequence(message)))?
+            .clone();
+        self.record_message(&message);
+        Some(message)
+    }
+
+    fn process_received_message(&mut self, midi_message: &wmidi::MidiMessage, stamp: u64) {
         let now = SystemTime::now();
+        let now_instant = Instant::now();
+
+        if self.backend_timestamps {
+            let offset = (now_instant - self.start).as_nanos() as i128 - stamp as i128 * 1000;
+            self.clock_offset = Some(self.clock_offset.map_or(offset, |o| o.min(offset)));
+        }
 
         let bytes = to_vec(midi_message);
 
         let item = self.pending_notes.remove(&bytes);
-        let insertion_time = match item {
+        let pending = match item {
             Some(item) => item,
             None => {
-                print!("Unexpected message received: {:?}", &midi_message);
+                if self.delivered.contains(&bytes) {
+                    self.reliability.duplicated += 1;
+                } else {
+                    self.reliability.unexpected += 1;
+                    println!("Unexpected message received: {:?}", &midi_message);
+                }
                 return;
             }
         };
 
-        let latency = now.duration_since(insertion_time).unwrap();
-        self.latencies.push(latency);
-    }
+        self.reliability.received += 1;
+        match self.last_received_sequence {
+            Some(last) if pending.sequence < last => self.reliability.out_of_order += 1,
+            _ => self.last_received_sequence = Some(pending.sequence),
+        }
 
-    fn print_analysis(&self) -> (Duration, Duration, Duration) {
-        let median: Duration = analysis::median(&self.latencies);
-        let mean: Duration = analysis::mean(&self.latencies);
-        let max: Duration = match self.latencies.iter().max() {
-            Some(max) => *max,
-            None => Duration::new(0, 0),
+        let latency = if self.backend_timestamps {
+            self.backend_timings.push(BackendTiming {
+                send: pending.send_instant - self.start,
+                stamp,
+            });
+            now_instant - pending.send_instant
+        } else {
+            now.duration_since(pending.send_time).unwrap_or_default()
         };
+        if latency > self.loss_timeout {
+            self.reliability.late += 1;
+        }
+
+        self.samples.push(Sample {
+            send_time: pending.send_time,
+            receive_time: now,
+            message: bytes.clone(),
+            latency,
+            driver_latency: None,
+        });
+        self.delivered.insert(bytes);
+    }
+
+    fn latencies(&self) -> Vec<Duration> {
+        self.samples.iter().map(|s| s.latency).collect()
+    }
+
+    fn driver_latencies(&self) -> Vec<Duration> {
+        let clock_offset = self.clock_offset.unwrap_or_default();
+        self.backend_timings
+            .iter()
+            .map(|timing| {
+                let received = timing.stamp as i128 * 1000 + clock_offset;
+                let latency = received - timing.send.as_nanos() as i128;
+                Duration::from_nanos(latency.max(0) as u64)
+            })
+            .collect()
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        let mut ret = self.samples.clone();
+        for (sample, driver_latency) in ret.iter_mut().zip(self.driver_latencies()) {
+            sample.driver_latency = Some(driver_latency);
+        }
+        ret
+    }
+
+    fn reliability(&self) -> Reliability {
+        let now = SystemTime::now();
+        let mut ret = self.reliability.clone();
+        for pending in self.pending_notes.values() {
+            let age = now.duration_since(pending.send_time).unwrap_or_default();
+            if age > self.loss_timeout {
+                ret.lost += 1;
+            } else {
+                ret.in_flight += 1;
+            }
+        }
+        ret
+    }
+
+    fn print_analysis(&self, histogram_bucket_width: Duration) -> Analysis {
+        let latencies = self.latencies();
+        if !self.backend_timestamps {
+            let summary = print_latencies(&latencies, Some(histogram_bucket_width));
+            let reliability = self.reliability();
+            println!("{}", reliability);
+
+            return Analysis {
+                summary,
+                driver_summary: None,
+                dispatch_summary: None,
+                reliability,
+            };
+        }
 
-        println!("Median: {:#?}, Mean: {:#?}, Max: {:#?}", median, mean, max);
-        (median, mean, max)
+        let driver_latencies = self.driver_latencies();
+        let dispatch_delays: Vec<_> = latencies
+            .iter()
+            .zip(&driver_latencies)
+            .map(|(latency, driver_latency)| latency.saturating_sub(*driver_latency))
+            .collect();
+
+        println!("Callback latency:");
+        let summary = print_latencies(&latencies, Some(histogram_bucket_width));
+        println!("Driver latency:");
+        let driver_summary = print_latencies(&driver_latencies, Some(histogram_bucket_width));
+        println!("Dispatch delay:");
+        let dispatch_summary = print_latencies(&dispatch_delays, None);
+
+        let reliability = self.reliability();
+        println!("{}", reliability);
+
+        Analysis {
+            summary,
+            driver_summary,
+            dispatch_summary,
+            reliability,
+        }
+    }
+}
+
+fn print_latencies(
+    latencies: &[Duration],
+    histogram_bucket_width: Option<Duration>,
+) -> Option<Summary> {
+    let summary = Summary::new(latencies);
+    match &summary {
+        Some(summary) => {
+            println!("{}", summary);
+            if let Some(bucket_width) = histogram_bucket_width {
+                print!("{}", Histogram::new(latencies, bucket_width));
+            }
+        }
+        None => println!("No messages received"),
     }
+    summary
 }
 
 pub struct LoopbackTimer {
@@ -63,9 +306,12 @@ pub struct LoopbackTimer {
 }
 
 impl LoopbackTimer {
-    pub fn new() -> Arc<Self> {
+    /// Messages that are not received within `loss_timeout` are considered lost. With
+    /// `backend_timestamps` latencies are measured with a monotonic clock and the receive
+    /// timestamps of the midi backend are evaluated in addition.
+    pub fn new(loss_timeout: Duration, backend_timestamps: bool) -> Arc<Self> {
         Arc::new(Self {
-            pimpl: Mutex::new(LoopbackTimerImpl::new()),
+            pimpl: Mutex::new(LoopbackTimerImpl::new(loss_timeout, backend_timestamps)),
         })
     }
 
@@ -73,38 +319,150 @@ impl LoopbackTimer {
         self.pimpl.lock().unwrap().record_message(midi_message);
     }
 
-    pub fn process_received_message(self: &Arc<Self>, midi_message: &wmidi::MidiMessage) {
+    /// Picks and records a candidate that is not in flight, in one step so that concurrent
+    /// senders never pick the same one. `None` if there are no candidates.
+    pub fn record_unique<'a>(
+        self: &Arc<Self>,
+        candidates: &[wmidi::MidiMessage<'a>],
+    ) -> Option<wmidi::MidiMessage<'a>> {
+        self.pimpl.lock().unwrap().record_unique(candidates)
+    }
+
+    /// `stamp` is the receive timestamp of the backend (in microseconds)
+    pub fn process_received_message(
+        self: &Arc<Self>,
+        midi_message: &wmidi::MidiMessage,
+        stamp: u64,
+    ) {
+        self.pimpl
+            .lock()
+            .unwrap()
+            .process_received_message(midi_message, stamp);
+    }
+
+    pub fn is_pending(self: &Arc<Self>, midi_message: &wmidi::MidiMessage) -> bool {
+        self.pimpl
+            .lock()
+            .unwrap()
+            .pending_notes
+            .contains_key(&to_vec(midi_message))
+    }
+
+    pub fn print_analysis(self: &Arc<Self>, histogram_bucket_width: Duration) -> Analysis {
         self.pimpl
             .lock()
             .unwrap()
-            .process_received_message(midi_message);
+            .print_analysis(histogram_bucket_width)
     }
 
-    pub fn print_analysis(self: &Arc<Self>) -> (Duration, Duration, Duration) {
-        self.pimpl.lock().unwrap().print_analysis()
+    /// Latency summary without printing anything
+    pub fn summary(self: &Arc<Self>) -> Option<Summary> {
+        Summary::new(&self.pimpl.lock().unwrap().latencies())
+    }
+
+    pub fn reliability(self: &Arc<Self>) -> Reliability {
+        self.pimpl.lock().unwrap().reliability()
+    }
+
+    pub fn samples(self: &Arc<Self>) -> Vec<Sample> {
+        self.pimpl.lock().unwrap().samples()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::loopback_timer::LoopbackTimerImpl;
     use std::time::Duration;
     use wmidi::Channel::Ch1;
-    use wmidi::MidiMessage::NoteOn;
+    use wmidi::MidiMessage::{NoteOff, NoteOn};
     use wmidi::{Note, Velocity};
 
     #[test]
     fn test_loopback_timer() {
-        let mut timer = crate::loopback_timer::LoopbackTimerImpl::new();
+        let mut timer = LoopbackTimerImpl::new(Duration::from_secs(1), false);
         let noteon = NoteOn(Ch1, Note::A0, Velocity::MAX);
 
         timer.record_message(&noteon);
         std::thread::sleep(Duration::from_millis(100));
-        timer.process_received_message(&noteon);
+        timer.process_received_message(&noteon, 0);
 
-        assert_eq!(timer.latencies.len(), 1);
+        assert_eq!(timer.samples.len(), 1);
         assert_eq!(timer.pending_notes.len(), 0);
-        let analysis = timer.print_analysis();
-        assert_ne!(analysis.0, Duration::from_secs_f32(0.0));
-        assert_ne!(analysis.1, Duration::from_secs_f32(0.0));
+        let analysis = timer.print_analysis(Duration::from_millis(1));
+        let summary = analysis.summary.unwrap();
+        assert_eq!(summary.count, 1);
+        assert_ne!(summary.p50, Duration::from_secs_f32(0.0));
+        assert_ne!(summary.mean, Duration::from_secs_f32(0.0));
+        assert_eq!(analysis.reliability.sent, 1);
+        assert_eq!(analysis.reliability.received, 1);
+        assert_eq!(analysis.reliability.lost, 0);
+    }
+
+    #[test]
+    fn test_reliability() {
+        let mut timer = LoopbackTimerImpl::new(Duration::from_millis(50), false);
+        let first = NoteOn(Ch1, Note::A0, Velocity::MAX);
+        let second = NoteOn(Ch1, Note::B0, Velocity::MAX);
+        let third = NoteOff(Ch1, Note::A0, Velocity::MIN);
+        let lost = NoteOff(Ch1, Note::B0, Velocity::MIN);
+
+        timer.record_message(&first);
+        timer.record_message(&second);
+        timer.record_message(&third);
+        timer.record_message(&lost);
+
+        timer.process_received_message(&second, 0);
+        timer.process_received_message(&first, 0);
+        timer.process_received_message(&first, 0);
+        timer.process_received_message(&NoteOn(Ch1, Note::C1, Velocity::MAX), 0);
+
+        let reliability = timer.reliability();
+        assert_eq!(reliability.sent, 4);
+        assert_eq!(reliability.received, 2);
+        assert_eq!(reliability.out_of_order, 1);
+        assert_eq!(reliability.duplicated, 1);
+        assert_eq!(reliability.unexpected, 1);
+        assert_eq!(reliability.in_flight, 2);
+        assert_eq!(reliability.lost, 0);
+
+        std::thread::sleep(Duration::from_millis(100));
+        timer.process_received_message(&third, 0);
+
+        let reliability = timer.reliability();
+        assert_eq!(reliability.received, 3);
+        assert_eq!(reliability.late, 1);
+        assert_eq!(reliability.in_flight, 0);
+        assert_eq!(reliability.lost, 1);
+    }
+
+    #[test]
+    fn test_backend_timestamps() {
+        let mut timer = LoopbackTimerImpl::new(Duration::from_secs(1), true);
+        let first = NoteOn(Ch1, Note::A0, Velocity::MAX);
+        let second = NoteOn(Ch1, Note::B0, Velocity::MAX);
+
+        // backend clock starts 10ms before the timer, the first callback is delayed by 5ms
+        let stamp = |received: Duration| (received + Duration::from_millis(10)).as_micros() as u64;
+
+        timer.record_message(&first);
+        timer.record_message(&second);
+        std::thread::sleep(Duration::from_millis(20));
+        let received = timer.start.elapsed() - Duration::from_millis(5);
+        timer.process_received_message(&first, stamp(received));
+        let received = timer.start.elapsed();
+        timer.process_received_message(&second, stamp(received));
+
+        let samples = timer.samples();
+        assert_eq!(samples.len(), 2);
+        for sample in &samples {
+            assert!(sample.driver_latency.unwrap() <= sample.latency);
+        }
+        let first_delay = samples[0].latency - samples[0].driver_latency.unwrap();
+        assert!(first_delay > Duration::from_millis(4));
+        assert!(first_delay < Duration::from_millis(6));
+
+        let analysis = timer.print_analysis(Duration::from_millis(1));
+        assert_eq!(analysis.driver_summary.unwrap().count, 2);
+        assert_eq!(analysis.dispatch_summary.unwrap().min, Duration::ZERO);
     }
 }
@@ -1,15 +1,29 @@
 mod analysis;
+mod clock;
 mod dump;
 mod echo;
+mod filter;
 mod generate;
 mod generator;
+mod impair;
 mod list_devices;
 mod loopback_timer;
+mod panic;
+mod play;
+mod report;
+mod route;
+mod smf;
+mod stress;
+mod sysex;
+mod transport;
 mod utils;
 
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use inline_colorization::*;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::time::Duration;
+use transport::{LoopbackTransport, MidirTransport, Transport};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -18,10 +32,23 @@ struct Cli {
     #[arg(short, long)]
     rt: bool,
 
+    /// Midi backend
+    #[arg(short, long, value_enum, default_value = "midir")]
+    backend: Backend,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(Clone, ValueEnum)]
+enum Backend {
+    /// System midi ports
+    Midir,
+
+    /// In-process loopback: output ports are connected to the input ports of the same name
+    Loopback,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// List all midi devices
@@ -40,12 +67,117 @@ enum Commands {
         #[arg(short, long)]
         /// Print message to command line
         print: bool,
+
+        #[arg(long, value_delimiter = ',')]
+        /// Message types that are not forwarded
+        drop: Vec<filter::MessageType>,
+
+        #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+        /// Channels (1-16) whose messages are not forwarded
+        drop_channels: Vec<u8>,
+
+        #[arg(long, value_delimiter = ',', value_parser = filter::parse_controller_mapping)]
+        /// Controller renumbering FROM:TO, for example 1:74
+        map_cc: Vec<(u8, u8)>,
+
+        #[arg(long, default_value = "0", allow_hyphen_values = true)]
+        /// Semitones to transpose notes by, notes shifted out of range are dropped
+        transpose: i8,
+
+        #[arg(long)]
+        /// Curve applied to NoteOn velocities
+        velocity_curve: Option<generator::VelocityCurve>,
+
+        #[arg(long, value_parser = filter::parse_velocity_scale)]
+        /// Factor NoteOn velocities are scaled by, after the curve
+        velocity_scale: Option<f64>,
+
+        #[arg(long, value_delimiter = ',', value_parser = filter::parse_channel_mapping)]
+        /// Channel remapping FROM:TO of channels 1-16, applied last
+        map_channel: Vec<(u8, u8)>,
+
+        #[arg(long, default_value = "0")]
+        /// Fixed delay added to every message (in microseconds)
+        delay: u32,
+
+        #[arg(long, default_value = "0")]
+        /// Random delay added on top of the fixed delay (in microseconds)
+        jitter: u32,
+
+        #[arg(long, value_enum, default_value_t = impair::JitterDistribution::Uniform)]
+        /// Distribution of the random delay
+        jitter_distribution: impair::JitterDistribution,
+
+        #[arg(long, default_value = "0", value_parser = parse_percentage)]
+        /// Percentage of messages that are lost
+        drop_percent: f64,
+
+        #[arg(long, default_value = "0", value_parser = parse_percentage)]
+        /// Percentage of messages that are sent twice
+        duplicate_percent: f64,
+
+        #[arg(long)]
+        /// Let the jitter reorder messages, otherwise they keep their order
+        reorder: bool,
+
+        #[arg(long)]
+        /// Seed of the drop, duplicate and jitter decisions, random if not given
+        seed: Option<u64>,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Route and merge several inputs to several outputs
+    Route {
+        #[arg(short, long = "route", required = true, value_parser = route::parse_route)]
+        /// Route INPUT=>OUTPUT, optionally followed by ;channels=1,2 ;drop=TYPES ;map-channel=FROM:TO
+        /// ;map-cc=FROM:TO or ;transpose=SEMITONES. Can be given several times.
+        routes: Vec<route::Route>,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
     },
 
     /// Print messages to command line
     Dump {
-        #[arg(short, long)]
-        input: String,
+        #[arg(short, long, required_unless_present = "all")]
+        /// Input device, can be given several times
+        input: Vec<String>,
+
+        #[arg(long, conflicts_with_all = ["input", "virtual_ports"])]
+        /// Dump all input ports
+        all: bool,
+
+        #[arg(long)]
+        /// Record the received messages to a Standard MIDI File (type 0)
+        record: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = dump::Format::Compact)]
+        /// Output format
+        format: dump::Format,
+
+        #[arg(long, value_delimiter = ',')]
+        /// Time fields printed before each message
+        time: Vec<dump::TimeField>,
+
+        #[arg(long)]
+        /// Measure deltas from the previous message on the same channel
+        per_channel_delta: bool,
+
+        #[arg(long, default_value = "6", value_parser = clap::value_parser!(u8).range(0..=9))]
+        /// Decimal places of the printed seconds
+        precision: u8,
+
+        #[arg(long)]
+        /// Stop with a non-zero exit code at the first message that cannot be decoded
+        fatal_errors: bool,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
     },
 
     /// Generate test notes
@@ -54,6 +186,26 @@ enum Commands {
         /// Note duration (in milliseconds)
         note_duration: u32,
 
+        #[arg(long)]
+        /// Randomise note durations between the note duration and this duration (in milliseconds)
+        max_note_duration: Option<u32>,
+
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=127))]
+        /// Notes started at the same time
+        chord_size: u8,
+
+        #[arg(long, value_enum, default_value = "random")]
+        /// How the notes of a chord are chosen from the note range and scale
+        chord_shape: generator::ChordShape,
+
+        #[arg(long)]
+        /// Highest number of notes held at the same time
+        max_polyphony: Option<u32>,
+
+        #[arg(long)]
+        /// Send All Notes Off and All Sound Off on all channels when stopping
+        all_notes_off: bool,
+
         /// Notes per second
         #[arg(long, default_value = "2")]
         notes_per_second: u32,
@@ -69,7 +221,296 @@ enum Commands {
         #[arg(short, long)]
         /// Validate loopback
         loopback_input: Option<String>,
+
+        #[arg(long)]
+        /// Vary velocities so that each looped back message can be matched unambiguously
+        unique_probes: bool,
+
+        #[arg(long)]
+        /// Seed of the random number generator, a run can be reproduced with the seed it printed
+        seed: Option<u64>,
+
+        #[arg(long, default_value = "0..127", value_parser = utils::parse_note_range)]
+        /// Range of notes, as numbers or names (e.g. C2..C6, C4 is 60)
+        note_range: RangeInclusive<u8>,
+
+        #[arg(long, value_enum, default_value = "chromatic")]
+        /// Only play notes of this scale
+        scale: generator::Scale,
+
+        #[arg(long, default_value = "C", value_parser = utils::parse_pitch_class)]
+        /// Root of the scale (e.g. A or F#)
+        root: u8,
+
+        #[arg(long, default_value = "1..127", value_parser = utils::parse_velocity_range)]
+        /// Range of velocities
+        velocity_range: RangeInclusive<u8>,
+
+        #[arg(long, value_enum, default_value = "linear")]
+        /// Distribution of the velocities within the range
+        velocity_curve: generator::VelocityCurve,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "1",
+            value_parser = clap::value_parser!(u8).range(1..=16)
+        )]
+        /// Midi channels (1-16)
+        channels: Vec<u8>,
+
+        #[arg(long, value_enum, default_value = "rotate")]
+        /// How notes are distributed over the channels
+        channel_mode: generator::ChannelMode,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "note",
+            value_parser = generator::parse_weighted_kind
+        )]
+        /// Message kinds with optional weights (e.g. note:4,cc:2,pitch-bend:1), kinds are note, cc,
+        /// cc14, pitch-bend, channel-pressure, poly-pressure and program-change
+        messages: Vec<(generator::MessageKind, u32)>,
+
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=119))]
+        /// Controller of CC sweeps, 14-bit sweeps need a controller below 32 and send the LSB on
+        /// controller + 32
+        controller: u8,
+
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+        /// Bucket width of the latency histogram (in microseconds)
+        histogram_bucket_width: u32,
+
+        #[arg(long, default_value = "1000")]
+        /// Messages not received back within this time are counted as lost (in milliseconds)
+        loss_timeout: u32,
+
+        #[arg(long)]
+        /// Measure latency with a monotonic clock and report the latency derived from the receive
+        /// timestamps of the midi backend separately
+        backend_timestamps: bool,
+
+        #[arg(long)]
+        /// Write all loopback samples and the summary to a JSON file
+        report_json: Option<PathBuf>,
+
+        #[arg(long)]
+        /// Write all loopback samples and the summary to a CSV file
+        report_csv: Option<PathBuf>,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Send midi clock with Start/Continue and Stop, optionally measure the jitter of the looped
+    /// back clock
+    Clock {
+        #[arg(short, long)]
+        /// Output device
+        output: String,
+
+        #[arg(short, long)]
+        /// Measure tempo and jitter of the clock received on this input
+        loopback_input: Option<String>,
+
+        #[arg(long, default_value = "120")]
+        /// Tempo (in beats per minute)
+        bpm: f64,
+
+        #[arg(long, value_parser = clap::value_parser!(u16).range(0..16384))]
+        /// Send this song position (in sixteenth notes) and Continue instead of Start
+        position: Option<u16>,
+
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+        /// Bucket width of the jitter histogram (in microseconds)
+        histogram_bucket_width: u32,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Follow the midi clock and transport messages of an input and report tempo and jitter
+    ClockMonitor {
+        #[arg(short, long)]
+        /// Input device
+        input: String,
+
+        #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u32).range(1..))]
+        /// Time between status lines (in milliseconds)
+        report_interval: u32,
+
+        #[arg(long, default_value = "96")]
+        /// Number of recent tick intervals the reported tempo and jitter are computed from
+        window: usize,
+
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+        /// Bucket width of the jitter histogram (in microseconds)
+        histogram_bucket_width: u32,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
     },
+
+    /// Send NoteOffs for all notes, All Notes Off and All Sound Off
+    Panic {
+        #[arg(short, long)]
+        /// Output device
+        output: String,
+
+        #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(1..=16))]
+        /// Midi channels (1-16), all channels by default
+        channels: Vec<u8>,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Play a Standard MIDI File (type 0 or 1)
+    Play {
+        /// Midi file
+        file: PathBuf,
+
+        #[arg(short, long)]
+        /// Output device
+        output: String,
+
+        #[arg(long, default_value = "0")]
+        /// Position at which playback starts (in milliseconds)
+        start: u32,
+
+        #[arg(long)]
+        /// Position at which playback stops (in milliseconds), defaults to the end of the file
+        end: Option<u32>,
+
+        #[arg(long = "loop")]
+        /// Repeat the section between start and end until interrupted
+        looping: bool,
+
+        #[arg(short, long)]
+        /// Print message to command line
+        print: bool,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Round-trip SysEx messages of increasing size and verify the echoes
+    Sysex {
+        #[arg(short, long)]
+        /// Output device
+        output: String,
+
+        #[arg(short, long)]
+        /// Loopback input device
+        loopback_input: String,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "8,64,256,1024,4096,16384,65536",
+            value_parser = clap::value_parser!(u32).range(sysex::MIN_SIZE as i64..=sysex::MAX_SIZE as i64)
+        )]
+        /// Message sizes including the F0/F7 framing (in bytes)
+        sizes: Vec<u32>,
+
+        #[arg(long, default_value = "10")]
+        /// Messages per size
+        count: u32,
+
+        #[arg(long, default_value = "1000")]
+        /// Messages not received back within this time are counted as lost (in milliseconds)
+        timeout: u32,
+
+        #[arg(short, long)]
+        /// Print message to command line
+        print: bool,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+
+    /// Ramp up the message rate until loss or latency thresholds are crossed
+    Stress {
+        #[arg(short, long)]
+        /// Output device
+        output: String,
+
+        #[arg(short, long)]
+        /// Loopback input device
+        loopback_input: String,
+
+        #[arg(long, default_value = "10")]
+        /// Initial rate (in messages per second)
+        start_rate: f64,
+
+        #[arg(long, default_value = "10000")]
+        /// Highest rate to test (in messages per second)
+        max_rate: f64,
+
+        #[arg(long, default_value = "1.25")]
+        /// Factor by which the rate is increased after each step
+        rate_factor: f64,
+
+        #[arg(long, default_value = "2000")]
+        /// Duration of each step (in milliseconds)
+        step_duration: u32,
+
+        #[arg(long, default_value = "10")]
+        /// Note duration (in milliseconds)
+        note_duration: u32,
+
+        #[arg(long, default_value = "1000")]
+        /// Messages not received back within this time are counted as lost (in milliseconds)
+        loss_timeout: u32,
+
+        #[arg(long, default_value = "0", value_parser = parse_percentage)]
+        /// Highest acceptable ratio of lost messages (in percent)
+        max_loss: f64,
+
+        #[arg(long, default_value = "10")]
+        /// Highest acceptable 99th percentile of the latency (in milliseconds)
+        max_latency: u32,
+
+        #[arg(short = 'V', long = "virtual")]
+        /// Create virtual ports with the given names instead of connecting to existing ones
+        virtual_ports: bool,
+    },
+}
+
+fn make_transport(
+    backend: &Backend,
+    virtual_ports: bool,
+) -> Result<Box<dyn Transport>, Box<dyn std::error::Error>> {
+    match backend {
+        Backend::Midir => Ok(Box::new(MidirTransport { virtual_ports })),
+        Backend::Loopback if virtual_ports => Err(Box::from(
+            "Virtual ports cannot be created with the loopback backend",
+        )),
+        Backend::Loopback => Ok(Box::new(LoopbackTransport::new())),
+    }
+}
+
+/// Parses a percentage between 0 and 100
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(percentage) if (0.0..=100.0).contains(&percentage) => Ok(percentage),
+        _ => Err(format!("Invalid percentage {:?}", s)),
+    }
+}
+
+/// Converts channel numbers (1-16) to channels
+fn to_channels(channels: &[u8]) -> Vec<wmidi::Channel> {
+    channels
+        .iter()
+        .map(|channel| wmidi::Channel::from_index(channel - 1).unwrap())
+        .collect()
 }
 
 fn main() {
@@ -80,40 +521,285 @@ fn main() {
         utils::acquire_rt_scheduling();
     }
 
-    let result = match &cli.command {
-        Some(Commands::ListDevices {}) => list_devices::list_devices(),
+    if let Err(e) = run(&cli) {
+        eprintln!("{color_red}{style_bold}{}{color_reset}{style_reset}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match &cli.command {
+        Some(Commands::ListDevices {}) => {
+            list_devices::list_devices(make_transport(&cli.backend, false)?.as_ref())
+        }
         Some(Commands::Echo {
             input,
             output,
             print,
-        }) => echo::echo(input, output, *print),
-        Some(Commands::Dump { input }) => dump::dump(input),
+            drop,
+            drop_channels,
+            map_cc,
+            transpose,
+            velocity_curve,
+            velocity_scale,
+            map_channel,
+            delay,
+            jitter,
+            jitter_distribution,
+            drop_percent,
+            duplicate_percent,
+            reorder,
+            seed,
+            virtual_ports,
+        }) => {
+            let impairments = impair::Impairments {
+                delay: Duration::from_micros((*delay).into()),
+                jitter: Duration::from_micros((*jitter).into()),
+                jitter_distribution: *jitter_distribution,
+                drop_probability: drop_percent / 100.0,
+                duplicate_probability: duplicate_percent / 100.0,
+                reorder: *reorder,
+                seed: seed.unwrap_or_else(rand::random),
+            };
+            if impairments.is_active() {
+                println!("Seed: {}", impairments.seed);
+            }
+            echo::echo(
+                make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+                input,
+                output,
+                filter::Filter {
+                    drop_types: drop.clone(),
+                    drop_channels: drop_channels.iter().map(|channel| channel - 1).collect(),
+                    controller_map: map_cc.clone(),
+                    transpose: *transpose,
+                    velocity_curve: *velocity_curve,
+                    velocity_scale: *velocity_scale,
+                    channel_map: map_channel.clone(),
+                },
+                impairments,
+                *print,
+            )
+        }
+        Some(Commands::Route {
+            routes,
+            virtual_ports,
+        }) => route::route(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            routes,
+        ),
+        Some(Commands::Dump {
+            input,
+            all,
+            record,
+            format,
+            time,
+            per_channel_delta,
+            precision,
+            fatal_errors,
+            virtual_ports,
+        }) => dump::dump(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            input,
+            *all,
+            record.as_deref(),
+            &dump::DumpOptions {
+                format: *format,
+                time_fields: time.clone(),
+                per_channel_delta: *per_channel_delta,
+                precision: (*precision).into(),
+                fatal_errors: *fatal_errors,
+                label_ports: false,
+            },
+        ),
         Some(Commands::Generate {
             note_duration,
+            max_note_duration,
+            chord_size,
+            chord_shape,
+            max_polyphony,
+            all_notes_off,
             notes_per_second,
             output,
             print,
             loopback_input,
-        }) => match loopback_input {
-            None => generate::generate_notes(
-                Duration::from_millis((*note_duration).into()),
-                Duration::from_secs(1) / *notes_per_second,
-                output,
-                *print,
-                None,
-            ),
-            Some(input_device) => generate::generate_and_analyse(
-                Duration::from_millis((*note_duration).into()),
-                Duration::from_secs(1) / *notes_per_second,
+            unique_probes,
+            seed,
+            note_range,
+            scale,
+            root,
+            velocity_range,
+            velocity_curve,
+            channels,
+            channel_mode,
+            messages,
+            controller,
+            histogram_bucket_width,
+            loss_timeout,
+            backend_timestamps,
+            report_json,
+            report_csv,
+            virtual_ports,
+        }) => {
+            let transport = make_transport(&cli.backend, *virtual_ports)?;
+            let seed = seed.unwrap_or_else(rand::random);
+            println!("Seed: {}", seed);
+            let options = generator::GeneratorOptions {
+                note_duration: Duration::from_millis((*note_duration).into()),
+                max_note_duration: max_note_duration
+                    .map(|duration| Duration::from_millis(duration.into())),
+                duration_between_notes: Duration::from_secs(1) / *notes_per_second,
+                print: *print,
+                unique_probes: *unique_probes,
+                seed,
+                notes: generator::scale_notes(note_range.clone(), *scale, *root),
+                velocities: velocity_range.clone(),
+                velocity_curve: *velocity_curve,
+                channels: to_channels(channels),
+                channel_mode: *channel_mode,
+                message_kinds: messages.clone(),
+                controller: *controller,
+                chord_size: *chord_size as usize,
+                chord_shape: *chord_shape,
+                max_polyphony: max_polyphony.map(|max_polyphony| max_polyphony as usize),
+                all_notes_off: *all_notes_off,
+            };
+            match loopback_input {
+                None => generate::generate_notes(transport.as_ref(), &options, output),
+                Some(input_device) => generate::generate_and_analyse(
+                    transport.as_ref(),
+                    &options,
+                    input_device,
+                    output,
+                    &generate::AnalysisOptions {
+                        histogram_bucket_width: Duration::from_micros(
+                            (*histogram_bucket_width).into(),
+                        ),
+                        loss_timeout: Duration::from_millis((*loss_timeout).into()),
+                        backend_timestamps: *backend_timestamps,
+                        report_json: report_json.clone(),
+                        report_csv: report_csv.clone(),
+                    },
+                ),
+            }
+        }
+        Some(Commands::Clock {
+            output,
+            loopback_input,
+            bpm,
+            position,
+            histogram_bucket_width,
+            virtual_ports,
+        }) => {
+            let transport = make_transport(&cli.backend, *virtual_ports)?;
+            let options = clock::ClockOptions {
+                bpm: *bpm,
+                position: *position,
+                histogram_bucket_width: Duration::from_micros((*histogram_bucket_width).into()),
+            };
+            match loopback_input {
+                None => clock::clock(transport.as_ref(), &options, output),
+                Some(input_device) => {
+                    clock::clock_and_analyse(transport.as_ref(), &options, input_device, output)
+                }
+            }
+        }
+        Some(Commands::ClockMonitor {
+            input,
+            report_interval,
+            window,
+            histogram_bucket_width,
+            virtual_ports,
+        }) => clock::clock_monitor(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            &clock::MonitorOptions {
+                report_interval: Duration::from_millis((*report_interval).into()),
+                window: *window,
+                histogram_bucket_width: Duration::from_micros((*histogram_bucket_width).into()),
+            },
+            input,
+        ),
+        Some(Commands::Panic {
+            output,
+            channels,
+            virtual_ports,
+        }) => {
+            let channels = if channels.is_empty() {
+                (1..=16).collect()
+            } else {
+                channels.clone()
+            };
+            panic::panic(
+                make_transport(&cli.backend, *virtual_ports)?.as_ref(),
                 output,
-                input_device,
-                *print,
-            ),
-        },
+                &to_channels(&channels),
+            )
+        }
+        Some(Commands::Play {
+            file,
+            output,
+            start,
+            end,
+            looping,
+            print,
+            virtual_ports,
+        }) => play::play(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            file,
+            &play::PlayOptions {
+                start: Duration::from_millis((*start).into()),
+                end: end.map(|end| Duration::from_millis(end.into())),
+                looping: *looping,
+                print: *print,
+            },
+            output,
+        ),
+        Some(Commands::Sysex {
+            output,
+            loopback_input,
+            sizes,
+            count,
+            timeout,
+            print,
+            virtual_ports,
+        }) => sysex::sysex_test(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            &sysex::SysexOptions {
+                sizes: sizes.iter().map(|size| *size as usize).collect(),
+                messages_per_size: *count,
+                timeout: Duration::from_millis((*timeout).into()),
+                print: *print,
+            },
+            loopback_input,
+            output,
+        ),
+        Some(Commands::Stress {
+            output,
+            loopback_input,
+            start_rate,
+            max_rate,
+            rate_factor,
+            step_duration,
+            note_duration,
+            loss_timeout,
+            max_loss,
+            max_latency,
+            virtual_ports,
+        }) => stress::stress_test(
+            make_transport(&cli.backend, *virtual_ports)?.as_ref(),
+            &stress::StressOptions {
+                start_rate: *start_rate,
+                max_rate: *max_rate,
+                rate_factor: *rate_factor,
+                step_duration: Duration::from_millis((*step_duration).into()),
+                note_duration: Duration::from_millis((*note_duration).into()),
+                loss_timeout: Duration::from_millis((*loss_timeout).into()),
+                max_loss: *max_loss / 100.0,
+                max_latency: Duration::from_millis((*max_latency).into()),
+            },
+            loopback_input,
+            output,
+        ),
         None => Ok(()),
-    };
-
-    if let Err(e) = result {
-        eprintln!("{color_red}{style_bold}{}{color_reset}{style_reset}", e);
     }
 }
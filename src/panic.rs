@@ -0,0 +1,62 @@
+use crate::transport::Transport;
+use crate::utils;
+use wmidi::MidiMessage::NoteOff;
+use wmidi::{Channel, MidiMessage, Velocity};
+
+/// NoteOffs for all notes followed by All Notes Off and All Sound Off, for each channel
+fn panic_messages(channels: &[Channel]) -> Vec<MidiMessage<'static>> {
+    channels
+        .iter()
+        .flat_map(|channel| {
+            utils::all_notes()
+                .into_iter()
+                .map(|note| NoteOff(*channel, note, Velocity::MIN))
+                .chain(utils::all_notes_off(*channel))
+        })
+        .collect()
+}
+
+pub fn panic(
+    transport: &dyn Transport,
+    output_device: &str,
+    channels: &[Channel],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connection = transport.connect_output(output_device)?;
+    for message in panic_messages(channels) {
+        connection.send(&utils::to_vec(&message))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::panic::panic;
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+    use wmidi::Channel::{Ch1, Ch16};
+
+    #[test]
+    fn test_panic() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let _input = transport
+            .connect_input(
+                "synth",
+                Box::new(move |_stamp, message: &[u8]| {
+                    captured.lock().unwrap().push(message.to_vec())
+                }),
+            )
+            .unwrap();
+
+        panic(&transport, "synth", &[Ch1, Ch16]).unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2 * 130);
+        assert_eq!(received[0], [0x80, 0, 0]);
+        assert_eq!(received[127], [0x80, 127, 0]);
+        assert_eq!(received[128], [0xb0, 123, 0]);
+        assert_eq!(received[129], [0xb0, 120, 0]);
+        assert_eq!(received[259], [0xbf, 120, 0]);
+    }
+}
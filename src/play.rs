@@ -0,0 +1,294 @@
+use crate::smf::{self, Event};
+use crate::transport::{OutputConnection, Transport};
+use crate::utils::wait_for_sigint;
+use fixedbitset::FixedBitSet;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::time::{sleep_until, Instant};
+use wmidi::MidiMessage;
+
+pub struct PlayOptions {
+    /// Position in the file at which playback starts
+    pub start: Duration,
+    /// Position in the file at which playback stops, the end of the file if `None`
+    pub end: Option<Duration>,
+    /// Repeat the section between start and end until interrupted
+    pub looping: bool,
+    pub print: bool,
+}
+
+struct Player {
+    output: Box<dyn OutputConnection>,
+    /// Indexed by channel * 128 + note
+    active_notes: FixedBitSet,
+    print: bool,
+    sent: u64,
+}
+
+impl Player {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match *message {
+            [status @ 0x90..=0x9f, note, velocity] if velocity > 0 => self
+                .active_notes
+                .insert((status & 0x0f) as usize * 128 + (note & 0x7f) as usize),
+            [status @ 0x80..=0x9f, note, _] => self.active_notes.set(
+                (status & 0x0f) as usize * 128 + (note & 0x7f) as usize,
+                false,
+            ),
+            _ => {}
+        }
+
+        if self.print {
+            match MidiMessage::from_bytes(message) {
+                Ok(message) => println!("Sent: {:?}", message),
+                Err(_) => println!("Sent: {:02x?}", message),
+            }
+        }
+        self.output.send(message)?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// Sends NoteOffs for all notes that are still sounding
+    fn release_notes(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let active_notes: Vec<_> = self.active_notes.ones().collect();
+        for index in active_notes {
+            self.send(&[0x80 | (index / 128) as u8, (index % 128) as u8, 0])?;
+        }
+        Ok(())
+    }
+}
+
+fn in_section(event: &Event, options: &PlayOptions) -> bool {
+    event.time >= options.start && options.end.is_none_or(|end| event.time < end)
+}
+
+/// Sends the last controller, program and pitch bend values before the start position, so that
+/// the section does not depend on the state left behind by the previous pass
+fn chase_state(
+    player: &mut Player,
+    events: &[Event],
+    start: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut state = BTreeMap::new();
+    for event in events.iter().take_while(|event| event.time < start) {
+        match event.message[..] {
+            [status @ 0xb0..=0xbf, controller, _] => {
+                state.insert((status, controller), &event.message);
+            }
+            [status @ 0xc0..=0xcf, ..] | [status @ 0xe0..=0xef, ..] => {
+                state.insert((status, 0), &event.message);
+            }
+            _ => {}
+        }
+    }
+
+    for message in state.into_values() {
+        player.send(message)?;
+    }
+    Ok(())
+}
+
+async fn play_section(
+    player: &mut Player,
+    events: &[Event],
+    options: &PlayOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    chase_state(player, events, options.start)?;
+
+    // events are scheduled relative to the start of the section, so delays do not accumulate
+    let start = Instant::now();
+    for event in events.iter().filter(|event| in_section(event, options)) {
+        sleep_until(start + (event.time - options.start)).await;
+        player.send(&event.message)?;
+    }
+    if let Some(end) = options.end {
+        sleep_until(start + end.saturating_sub(options.start)).await;
+    }
+
+    player.release_notes()
+}
+
+fn run_player(
+    transport: &dyn Transport,
+    events: &[Event],
+    options: &PlayOptions,
+    output_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if !events.iter().any(|event| in_section(event, options)) {
+        return Err(Box::from("No events between start and end position"));
+    }
+
+    let mut player = Player {
+        output: transport.connect_output(output_device)?,
+        active_notes: FixedBitSet::with_capacity(16 * 128),
+        print: options.print,
+        sent: 0,
+    };
+
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        let run = async {
+            loop {
+                play_section(&mut player, events, options).await?;
+                if !options.looping {
+                    break;
+                }
+            }
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+
+        tokio::select! {
+            result = run => result?,
+            _ = stop => player.release_notes()?,
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
+    })?;
+    Ok(player.sent)
+}
+
+pub fn play(
+    transport: &dyn Transport,
+    path: &Path,
+    options: &PlayOptions,
+    output_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let events = smf::read_file(path)?;
+    run_player(
+        transport,
+        &events,
+        options,
+        output_device,
+        wait_for_sigint(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::play::{run_player, PlayOptions};
+    use crate::smf::Event;
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn event(time_ms: u64, message: &[u8]) -> Event {
+        Event {
+            time: Duration::from_millis(time_ms),
+            message: message.to_vec(),
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            event(0, &[0xb0, 7, 10]),
+            event(0, &[0x90, 60, 100]),
+            event(10, &[0xb0, 7, 20]),
+            event(20, &[0x80, 60, 0]),
+            event(30, &[0x90, 62, 100]),
+            event(60, &[0x80, 62, 0]),
+        ]
+    }
+
+    fn play(options: &PlayOptions, stop_after: Duration) -> Vec<(Duration, Vec<u8>)> {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let start = Instant::now();
+        let _input = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_stamp, message: &[u8]| {
+                    captured
+                        .lock()
+                        .unwrap()
+                        .push((start.elapsed(), message.to_vec()))
+                }),
+            )
+            .unwrap();
+
+        let sent = run_player(&transport, &events(), options, "loop", async {
+            tokio::time::sleep(stop_after).await
+        })
+        .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(sent as usize, received.len());
+        received
+    }
+
+    #[test]
+    fn test_play() {
+        let received = play(
+            &PlayOptions {
+                start: Duration::ZERO,
+                end: None,
+                looping: false,
+                print: false,
+            },
+            Duration::from_secs(10),
+        );
+
+        let messages: Vec<_> = received
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect();
+        let expected: Vec<_> = events().into_iter().map(|event| event.message).collect();
+        assert_eq!(messages, expected);
+        assert!(received[5].0 >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_play_section() {
+        // starts after the first CC and ends while the second note is sounding
+        let received = play(
+            &PlayOptions {
+                start: Duration::from_millis(15),
+                end: Some(Duration::from_millis(40)),
+                looping: false,
+                print: false,
+            },
+            Duration::from_secs(10),
+        );
+
+        let messages: Vec<_> = received
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                vec![0xb0, 7, 20],
+                vec![0x80, 60, 0],
+                vec![0x90, 62, 100],
+                vec![0x80, 62, 0],
+            ]
+        );
+        assert!(received[3].0 >= Duration::from_millis(25));
+    }
+
+    #[test]
+    fn test_play_loop() {
+        let received = play(
+            &PlayOptions {
+                start: Duration::from_millis(30),
+                end: Some(Duration::from_millis(50)),
+                looping: true,
+                print: false,
+            },
+            Duration::from_millis(70),
+        );
+
+        let note_ons = received
+            .iter()
+            .filter(|(_, message)| message[0] == 0x90)
+            .count();
+        assert!(note_ons >= 3);
+        // the note sounding when playback is stopped is released
+        assert_eq!(received.last().unwrap().1, [0x80, 62, 0]);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::analysis::Summary;
+use crate::loopback_timer::{Analysis, Reliability, Sample};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn micros_since_epoch(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+fn micros(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1e6)
+}
+
+fn optional_micros(duration: Option<Duration>, none: &str) -> String {
+    duration.map_or(none.to_string(), micros)
+}
+
+fn reliability_fields(reliability: &Reliability) -> [(&'static str, String); 8] {
+    [
+        ("sent", reliability.sent.to_string()),
+        ("received", reliability.received.to_string()),
+        ("lost", reliability.lost.to_string()),
+        ("duplicated", reliability.duplicated.to_string()),
+        ("out_of_order", reliability.out_of_order.to_string()),
+        ("late", reliability.late.to_string()),
+        ("unexpected", reliability.unexpected.to_string()),
+        ("in_flight", reliability.in_flight.to_string()),
+    ]
+}
+
+/// Named summaries of an analysis, summaries that were not computed are `None`
+fn summaries(analysis: &Analysis) -> [(&'static str, Option<&Summary>); 3] {
+    [
+        ("summary", analysis.summary.as_ref()),
+        ("driver_summary", analysis.driver_summary.as_ref()),
+        ("dispatch_summary", analysis.dispatch_summary.as_ref()),
+    ]
+}
+
+pub fn json_object(fields: &[(&'static str, String)]) -> String {
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|(key, value)| format!("\"{}\": {}", key, value))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// A JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn summary_fields(summary: &Summary) -> [(&'static str, String); 9] {
+    [
+        ("count", summary.count.to_string()),
+        ("min_us", micros(summary.min)),
+        ("max_us", micros(summary.max)),
+        ("mean_us", micros(summary.mean)),
+        ("std_dev_us", micros(summary.std_dev)),
+        ("p50_us", micros(summary.p50)),
+        ("p90_us", micros(summary.p90)),
+        ("p99_us", micros(summary.p99)),
+        ("p99_9_us", micros(summary.p999)),
+    ]
+}
+
+pub fn write_json(
+    writer: &mut impl Write,
+    samples: &[Sample],
+    analysis: &Analysis,
+) -> std::io::Result<()> {
+    write!(writer, "{{")?;
+    for (name, summary) in summaries(analysis) {
+        match summary {
+            Some(summary) => write!(
+                writer,
+                "\n  \"{}\": {},",
+                name,
+                json_object(&summary_fields(summary))
+            )?,
+            None => write!(writer, "\n  \"{}\": null,", name)?,
+        }
+    }
+    write!(
+        writer,
+        "\n  \"reliability\": {}",
+        json_object(&reliability_fields(&analysis.reliability))
+    )?;
+
+    write!(writer, ",\n  \"samples\": [")?;
+    for (i, sample) in samples.iter().enumerate() {
+        let message: Vec<_> = sample.message.iter().map(|b| b.to_string()).collect();
+        write!(
+            writer,
+            "{}\n    {{\"send_time_us\": {}, \"receive_time_us\": {}, \"message\": [{}], \"latency_us\": {}, \"driver_latency_us\": {}}}",
+            if i == 0 { "" } else { "," },
+            micros_since_epoch(sample.send_time),
+            micros_since_epoch(sample.receive_time),
+            message.join(", "),
+            micros(sample.latency),
+            optional_micros(sample.driver_latency, "null")
+        )?;
+    }
+    writeln!(writer, "\n  ]\n}}")
+}
+
+/// Writes one line per sample, the summaries are prepended as `#` comment lines
+pub fn write_csv(
+    writer: &mut impl Write,
+    samples: &[Sample],
+    analysis: &Analysis,
+) -> std::io::Result<()> {
+    for (name, summary) in summaries(analysis) {
+        if let Some(summary) = summary {
+            for (key, value) in summary_fields(summary) {
+                let prefix = name.strip_suffix("summary").unwrap();
+                writeln!(writer, "# {}{}: {}", prefix, key, value)?;
+            }
+        }
+    }
+    for (key, value) in reliability_fields(&analysis.reliability) {
+        writeln!(writer, "# {}: {}", key, value)?;
+    }
+
+    writeln!(
+        writer,
+        "send_time_us,receive_time_us,message,latency_us,driver_latency_us"
+    )?;
+    for sample in samples {
+        let message: Vec<_> = sample
+            .message
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            micros_since_epoch(sample.send_time),
+            micros_since_epoch(sample.receive_time),
+            message.join(" "),
+            micros(sample.latency),
+            optional_micros(sample.driver_latency, "")
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_json_file(
+    path: &Path,
+    samples: &[Sample],
+    analysis: &Analysis,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_json(&mut writer, samples, analysis)?;
+    writer.flush()
+}
+
+pub fn write_csv_file(path: &Path, samples: &[Sample], analysis: &Analysis) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_csv(&mut writer, samples, analysis)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::Summary;
+    use crate::loopback_timer::{Analysis, Reliability, Sample};
+    use crate::report::{write_csv, write_json};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn samples() -> Vec<Sample> {
+        [(1_000, 1_250), (2_000, 2_500)]
+            .iter()
+            .map(|(send, receive)| Sample {
+                send_time: UNIX_EPOCH + Duration::from_micros(*send),
+                receive_time: UNIX_EPOCH + Duration::from_micros(*receive),
+                message: heapless::Vec::from_slice(&[0x90, 60, 100]).unwrap(),
+                latency: Duration::from_micros(receive - send),
+                driver_latency: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let samples = samples();
+        let latencies: Vec<_> = samples.iter().map(|s| s.latency).collect();
+        let mut out = Vec::new();
+        let analysis = Analysis {
+            summary: Summary::new(&latencies),
+            driver_summary: None,
+            dispatch_summary: Summary::new(&latencies),
+            reliability: Reliability {
+                sent: 3,
+                received: 2,
+                lost: 1,
+                ..Default::default()
+            },
+        };
+        write_csv(&mut out, &samples, &analysis).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("# count: 2\n# min_us: 250.000\n"));
+        assert!(out.contains("# dispatch_p50_us: 500.000\n"));
+        assert!(!out.contains("# driver_"));
+        assert!(out.contains("# sent: 3\n# received: 2\n# lost: 1\n"));
+        assert!(out.ends_with(
+            "send_time_us,receive_time_us,message,latency_us,driver_latency_us\n\
+             1000,1250,90 3c 64,250.000,\n\
+             2000,2500,90 3c 64,500.000,\n"
+        ));
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        let analysis = Analysis {
+            summary: None,
+            driver_summary: None,
+            dispatch_summary: None,
+            reliability: Default::default(),
+        };
+        write_json(&mut out, &samples()[..1], &analysis).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\n  \"summary\": null,\n  \"driver_summary\": null,\n  \"dispatch_summary\": null,\n  \
+             \"reliability\": {\"sent\": 0, \"received\": 0, \"lost\": 0, \"duplicated\": 0, \
+             \"out_of_order\": 0, \"late\": 0, \"unexpected\": 0, \"in_flight\": 0},\n  \
+             \"samples\": [\n    \
+             {\"send_time_us\": 1000, \"receive_time_us\": 1250, \"message\": [144, 60, 100], \"latency_us\": 250.000, \"driver_latency_us\": null}\n  \
+             ]\n}\n"
+        );
+    }
+}
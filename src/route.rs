@@ -0,0 +1,410 @@
+use crate::filter::{self, Filter, MessageType};
+use crate::transport::{InputConnection, OutputConnection, Transport};
+use crate::utils::loop_until_sigint;
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An unterminated SysEx message is given up if its input sends nothing for this long
+const SYSEX_TIMEOUT: Duration = Duration::from_millis(500);
+/// Messages held back at most while another input sends SysEx
+const MAX_PENDING: usize = 1024;
+
+#[derive(Clone)]
+pub struct Route {
+    pub input: String,
+    pub output: String,
+    pub filter: Filter,
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    value.split(',').map(parse).collect()
+}
+
+fn parse_channel(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(channel @ 1..=16) => Ok(channel - 1),
+        _ => Err(format!("Invalid channel {:?}", s)),
+    }
+}
+
+/// Parses a route `INPUT=>OUTPUT[;OPTION=VALUE...]`, with the options `channels` (messages of other
+/// channels are not routed), `drop`, `map-channel`, `map-cc` and `transpose`
+pub fn parse_route(s: &str) -> Result<Route, String> {
+    let mut parts = s.split(';');
+    let (input, output) = parts
+        .next()
+        .and_then(|ports| ports.split_once("=>"))
+        .filter(|(input, output)| !input.is_empty() && !output.is_empty())
+        .ok_or_else(|| format!("Invalid route {:?}, expected INPUT=>OUTPUT", s))?;
+
+    let mut filter = Filter::default();
+    for option in parts {
+        let (key, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid route option {:?}", option))?;
+        match key {
+            "channels" => {
+                let channels = parse_list(value, parse_channel)?;
+                filter.drop_channels = (0..16).filter(|c| !channels.contains(c)).collect();
+            }
+            "drop" => {
+                filter.drop_types = parse_list(value, |kind| MessageType::from_str(kind, true))?
+            }
+            "map-channel" => filter.channel_map = parse_list(value, filter::parse_channel_mapping)?,
+            "map-cc" => {
+                filter.controller_map = parse_list(value, filter::parse_controller_mapping)?
+            }
+            "transpose" => {
+                filter.transpose = value
+                    .parse()
+                    .map_err(|_| format!("Invalid transposition {:?}", value))?
+            }
+            _ => return Err(format!("Unknown route option {:?}", key)),
+        }
+    }
+
+    Ok(Route {
+        input: input.to_string(),
+        output: output.to_string(),
+        filter,
+    })
+}
+
+/// Tracks whether an input is in the middle of a SysEx message that arrives in several chunks
+#[derive(Default)]
+struct SysexState {
+    open: bool,
+}
+
+impl SysexState {
+    /// Whether the message continues an unterminated SysEx message
+    fn is_continuation(&mut self, message: &[u8]) -> bool {
+        let continuation = self.open && matches!(message.first(), Some(0x00..=0x7f | 0xf7));
+        match message.first() {
+            Some(0xf8..=0xff) => {}
+            Some(0xf0) => self.open = message.last() != Some(&0xf7),
+            _ if continuation => self.open = message.last() != Some(&0xf7),
+            _ => self.open = false,
+        }
+        continuation
+    }
+}
+
+/// An output shared by all routes leading to it.
+/// While one input is in the middle of a SysEx message, messages from the other inputs are held
+/// back, except for realtime messages which may appear anywhere. The SysEx message is given up
+/// once its input stalls for `SYSEX_TIMEOUT`, or when too many messages are held back; its later
+/// chunks are dropped.
+struct RoutedOutput {
+    connection: Box<dyn OutputConnection>,
+    /// Index of the input with an unterminated SysEx message and the time of its last chunk
+    sysex_owner: Option<(usize, Instant)>,
+    /// Index of the input whose SysEx message was given up
+    abandoned: Option<usize>,
+    pending: Vec<(usize, Vec<u8>)>,
+}
+
+impl RoutedOutput {
+    fn new(connection: Box<dyn OutputConnection>) -> Self {
+        RoutedOutput {
+            connection,
+            sysex_owner: None,
+            abandoned: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Gives up the SysEx message if its input stalled
+    fn expire_sysex(&mut self) {
+        if self
+            .sysex_owner
+            .is_some_and(|(_, last_chunk)| last_chunk.elapsed() >= SYSEX_TIMEOUT)
+        {
+            self.abandon_sysex();
+        }
+    }
+
+    fn abandon_sysex(&mut self) {
+        self.abandoned = self.sysex_owner.map(|(owner, _)| owner);
+        self.release_sysex();
+    }
+
+    fn release_sysex(&mut self) {
+        self.sysex_owner = None;
+        for (input, message) in std::mem::take(&mut self.pending) {
+            self.send(input, &message);
+        }
+    }
+
+    fn write(&mut self, message: &[u8]) {
+        self.connection
+            .send(message)
+            .unwrap_or_else(|e| println!("MidiIO error: {}", e));
+    }
+
+    fn send(&mut self, input: usize, message: &[u8]) {
+        let Some(&status) = message.first() else {
+            return;
+        };
+        if status >= 0xf8 {
+            self.write(message);
+            return;
+        }
+        self.expire_sysex();
+        // later chunks of a given up SysEx message would arrive as stray data bytes
+        if self.abandoned == Some(input) {
+            if status < 0x80 || status == 0xf7 {
+                if message.last() == Some(&0xf7) {
+                    self.abandoned = None;
+                }
+                return;
+            }
+            self.abandoned = None;
+        }
+        if let Some((owner, _)) = self.sysex_owner {
+            if owner != input {
+                self.pending.push((input, message.to_vec()));
+                if self.pending.len() >= MAX_PENDING {
+                    self.abandon_sysex();
+                }
+                return;
+            }
+        }
+
+        self.write(message);
+        let owned = self.sysex_owner.is_some();
+        let terminated = message.last() == Some(&0xf7);
+        let opens = status == 0xf0 && !terminated;
+        // any status byte from the owner ends its SysEx message
+        let continues = owned && status < 0x80 && !terminated;
+        self.sysex_owner = (opens || continues).then_some((input, Instant::now()));
+        if owned && self.sysex_owner.is_none() {
+            self.release_sysex();
+        }
+    }
+}
+
+/// Sends the messages held back by stalled SysEx messages, also if no further message arrives.
+/// Returns once the outputs are dropped with the input connections.
+fn watch_sysex(outputs: Vec<Weak<Mutex<RoutedOutput>>>) {
+    loop {
+        thread::sleep(SYSEX_TIMEOUT / 10);
+        let mut connected = false;
+        for output in outputs.iter().filter_map(Weak::upgrade) {
+            connected = true;
+            output.lock().unwrap().expire_sysex();
+        }
+        if !connected {
+            return;
+        }
+    }
+}
+
+fn connect_routes(
+    transport: &dyn Transport,
+    routes: &[Route],
+) -> Result<Vec<InputConnection>, Box<dyn std::error::Error>> {
+    let mut outputs: HashMap<&str, Arc<Mutex<RoutedOutput>>> = HashMap::new();
+    let mut inputs: Vec<&str> = Vec::new();
+    for route in routes {
+        if !outputs.contains_key(route.output.as_str()) {
+            let output = RoutedOutput::new(transport.connect_output(&route.output)?);
+            outputs.insert(&route.output, Arc::new(Mutex::new(output)));
+        }
+        if !inputs.contains(&route.input.as_str()) {
+            inputs.push(&route.input);
+        }
+    }
+
+    let mut connections = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let targets: Vec<_> = routes
+            .iter()
+            .filter(|route| route.input == input)
+            .map(|route| (route.filter.clone(), outputs[route.output.as_str()].clone()))
+            .collect();
+        let mut sysex = SysexState::default();
+
+        connections.push(transport.connect_input(
+            input,
+            Box::new(move |_stamp, message| {
+                let continuation = sysex.is_continuation(message);
+                for (filter, output) in &targets {
+                    let message = match continuation {
+                        true => (!filter.drop_types.contains(&MessageType::Sysex))
+                            .then_some(Cow::Borrowed(message)),
+                        false => filter.apply(message),
+                    };
+                    if let Some(message) = message {
+                        output.lock().unwrap().send(index, &message);
+                    }
+                }
+            }),
+        )?);
+    }
+
+    let watched = outputs.values().map(Arc::downgrade).collect();
+    thread::spawn(move || watch_sysex(watched));
+    Ok(connections)
+}
+
+pub fn route(
+    transport: &dyn Transport,
+    routes: &[Route],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _connections = connect_routes(transport, routes)?;
+    loop_until_sigint()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::MessageType;
+    use crate::route::{connect_routes, parse_route, MAX_PENDING, SYSEX_TIMEOUT};
+    use crate::transport::{InputConnection, LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+
+    type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    fn capture(transport: &LoopbackTransport, port: &str) -> (InputConnection, Received) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let captured = received.clone();
+        let connection = transport
+            .connect_input(
+                port,
+                Box::new(move |_, message: &[u8]| captured.lock().unwrap().push(message.to_vec())),
+            )
+            .unwrap();
+        (connection, received)
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let route =
+            parse_route("Keys=>Synth 1;channels=1,10;drop=cc,realtime;transpose=-12").unwrap();
+        assert_eq!(route.input, "Keys");
+        assert_eq!(route.output, "Synth 1");
+        assert_eq!(route.filter.drop_channels.len(), 14);
+        assert!(!route.filter.drop_channels.contains(&9));
+        assert_eq!(
+            route.filter.drop_types,
+            [MessageType::Cc, MessageType::Realtime]
+        );
+        assert_eq!(route.filter.transpose, -12);
+
+        assert!(parse_route("Keys").is_err());
+        assert!(parse_route("=>Synth").is_err());
+        assert!(parse_route("Keys=>Synth;channels=17").is_err());
+        assert!(parse_route("Keys=>Synth;volume=1").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let transport = LoopbackTransport::new();
+        let (_synth, synth) = capture(&transport, "synth");
+        let (_drums, drums) = capture(&transport, "drums");
+        let routes = [
+            parse_route("keys=>synth;channels=1").unwrap(),
+            parse_route("keys=>drums;channels=10;map-channel=10:1").unwrap(),
+            parse_route("pads=>drums;drop=realtime").unwrap(),
+        ];
+        let _router = connect_routes(&transport, &routes).unwrap();
+
+        let mut keys = transport.connect_output("keys").unwrap();
+        let mut pads = transport.connect_output("pads").unwrap();
+        keys.send(&[0x90, 60, 100]).unwrap();
+        keys.send(&[0x99, 36, 100]).unwrap();
+        pads.send(&[0xf8]).unwrap();
+        pads.send(&[0x91, 40, 100]).unwrap();
+
+        assert_eq!(*synth.lock().unwrap(), [vec![0x90, 60, 100]]);
+        assert_eq!(
+            *drums.lock().unwrap(),
+            [vec![0x90, 36, 100], vec![0x91, 40, 100]]
+        );
+    }
+
+    #[test]
+    fn test_route_sysex_chunks() {
+        let transport = LoopbackTransport::new();
+        let (_synth, synth) = capture(&transport, "synth");
+        let routes = [
+            parse_route("a=>synth").unwrap(),
+            parse_route("b=>synth").unwrap(),
+        ];
+        let _router = connect_routes(&transport, &routes).unwrap();
+
+        let mut a = transport.connect_output("a").unwrap();
+        let mut b = transport.connect_output("b").unwrap();
+        a.send(&[0xf0, 1, 2]).unwrap();
+        b.send(&[0x90, 60, 100]).unwrap();
+        b.send(&[0xf0, 5]).unwrap();
+        b.send(&[0xf8]).unwrap();
+        a.send(&[3, 0xf7]).unwrap();
+        b.send(&[6, 0xf7]).unwrap();
+
+        assert_eq!(
+            *synth.lock().unwrap(),
+            [
+                vec![0xf0, 1, 2],
+                vec![0xf8],
+                vec![3, 0xf7],
+                vec![0x90, 60, 100],
+                vec![0xf0, 5],
+                vec![6, 0xf7],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_route_unterminated_sysex() {
+        let transport = LoopbackTransport::new();
+        let (_synth, synth) = capture(&transport, "synth");
+        let routes = [
+            parse_route("a=>synth").unwrap(),
+            parse_route("b=>synth").unwrap(),
+        ];
+        let _router = connect_routes(&transport, &routes).unwrap();
+
+        let mut a = transport.connect_output("a").unwrap();
+        let mut b = transport.connect_output("b").unwrap();
+
+        // a status byte from the owner ends the SysEx message
+        a.send(&[0xf0, 1, 2]).unwrap();
+        b.send(&[0x90, 60, 100]).unwrap();
+        a.send(&[0x80, 61, 0]).unwrap();
+        assert_eq!(
+            *synth.lock().unwrap(),
+            [vec![0xf0, 1, 2], vec![0x80, 61, 0], vec![0x90, 60, 100]]
+        );
+        synth.lock().unwrap().clear();
+
+        // the owner stalls, the held back message is sent without waiting for another message
+        a.send(&[0xf0, 3]).unwrap();
+        b.send(&[0x90, 62, 100]).unwrap();
+        assert_eq!(*synth.lock().unwrap(), [vec![0xf0, 3]]);
+        sleep(SYSEX_TIMEOUT + SYSEX_TIMEOUT / 2);
+        assert_eq!(*synth.lock().unwrap(), [vec![0xf0, 3], vec![0x90, 62, 100]]);
+
+        // the rest of the given up message is dropped
+        a.send(&[4, 5]).unwrap();
+        a.send(&[6, 0xf7]).unwrap();
+        a.send(&[0x90, 63, 100]).unwrap();
+        assert_eq!(
+            *synth.lock().unwrap(),
+            [vec![0xf0, 3], vec![0x90, 62, 100], vec![0x90, 63, 100]]
+        );
+        synth.lock().unwrap().clear();
+
+        // too many messages held back
+        a.send(&[0xf0, 4]).unwrap();
+        for _ in 0..MAX_PENDING {
+            b.send(&[0xe0, 0, 64]).unwrap();
+        }
+        assert_eq!(synth.lock().unwrap().len(), MAX_PENDING + 1);
+    }
+}
@@ -0,0 +1,459 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Ticks per quarter note of recorded files
+pub const DEFAULT_PPQ: u16 = 960;
+
+/// Microseconds per quarter note of recorded files (120 BPM)
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+pub fn write_variable_length(writer: &mut impl Write, value: u32) -> std::io::Result<usize> {
+    let mut buffer = [0u8; 5];
+    let mut index = buffer.len() - 1;
+    let mut value = value;
+
+    buffer[index] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value != 0 {
+        index -= 1;
+        buffer[index] = (value & 0x7f) as u8 | 0x80;
+        value >>= 7;
+    }
+
+    writer.write_all(&buffer[index..])?;
+    Ok(buffer.len() - index)
+}
+
+fn read_variable_length(data: &[u8], position: &mut usize) -> Result<u32, Box<dyn Error>> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+        let byte = *data
+            .get(*position)
+            .ok_or("Truncated variable length quantity")?;
+        *position += 1;
+        value = (value << 7) | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Box::from("Invalid variable length quantity"))
+}
+
+fn read_bytes<'a>(
+    data: &'a [u8],
+    position: &mut usize,
+    length: usize,
+) -> Result<&'a [u8], Box<dyn Error>> {
+    let bytes = data
+        .get(*position..*position + length)
+        .ok_or("Truncated midi file")?;
+    *position += length;
+    Ok(bytes)
+}
+
+/// Message with its time relative to the start of the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub time: Duration,
+    pub message: Vec<u8>,
+}
+
+enum TrackEvent {
+    Message(Vec<u8>),
+    /// Microseconds per quarter note
+    Tempo(u32),
+}
+
+fn read_track(data: &[u8]) -> Result<Vec<(u64, TrackEvent)>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    let mut position = 0;
+    let mut tick = 0u64;
+    let mut running_status = None;
+
+    while position < data.len() {
+        tick += read_variable_length(data, &mut position)? as u64;
+
+        let mut status = *data.get(position).ok_or("Truncated midi file")?;
+        if status & 0x80 == 0 {
+            status = running_status.ok_or("Running status without previous status byte")?;
+        } else {
+            position += 1;
+        }
+
+        match status {
+            0x80..=0xef => {
+                running_status = Some(status);
+                let data_bytes = if matches!(status & 0xf0, 0xc0 | 0xd0) {
+                    1
+                } else {
+                    2
+                };
+                let mut message = vec![status];
+                message.extend_from_slice(read_bytes(data, &mut position, data_bytes)?);
+                ret.push((tick, TrackEvent::Message(message)));
+            }
+            0xf0 | 0xf7 => {
+                running_status = None;
+                let length = read_variable_length(data, &mut position)? as usize;
+                let bytes = read_bytes(data, &mut position, length)?;
+                // escape sequences (F7) are sent as they are
+                let mut message = if status == 0xf0 {
+                    vec![0xf0]
+                } else {
+                    Vec::new()
+                };
+                message.extend_from_slice(bytes);
+                if !message.is_empty() {
+                    ret.push((tick, TrackEvent::Message(message)));
+                }
+            }
+            0xff => {
+                running_status = None;
+                let meta_type = *read_bytes(data, &mut position, 1)?.first().unwrap();
+                let length = read_variable_length(data, &mut position)? as usize;
+                let bytes = read_bytes(data, &mut position, length)?;
+                match (meta_type, bytes) {
+                    (0x51, [a, b, c]) => {
+                        ret.push((tick, TrackEvent::Tempo(u32::from_be_bytes([0, *a, *b, *c]))))
+                    }
+                    (0x2f, _) => break,
+                    _ => {}
+                }
+            }
+            _ => return Err(Box::from(format!("Invalid status byte {:#04x}", status))),
+        }
+    }
+    Ok(ret)
+}
+
+/// Reads a Type 0 or Type 1 Standard MIDI File, the events of all tracks are merged and their
+/// times are derived from the division and the tempo map
+pub fn read_events(data: &[u8]) -> Result<Vec<Event>, Box<dyn Error>> {
+    let mut position = 0;
+    if read_bytes(data, &mut position, 4)? != b"MThd" {
+        return Err(Box::from("Not a Standard MIDI File"));
+    }
+    let header_length = u32::from_be_bytes(read_bytes(data, &mut position, 4)?.try_into()?);
+    let header = read_bytes(data, &mut position, header_length as usize)?;
+    if header.len() < 6 {
+        return Err(Box::from("Invalid midi file header"));
+    }
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if format > 1 {
+        return Err(Box::from(format!(
+            "Midi file format {} is not supported",
+            format
+        )));
+    }
+
+    let mut events = Vec::new();
+    while position < data.len() {
+        let chunk_type = read_bytes(data, &mut position, 4)?;
+        let length = u32::from_be_bytes(read_bytes(data, &mut position, 4)?.try_into()?);
+        let chunk = read_bytes(data, &mut position, length as usize)?;
+        // unknown chunks have to be skipped
+        if chunk_type == b"MTrk" {
+            events.extend(read_track(chunk)?);
+        }
+    }
+    // stable, so simultaneous events keep the order of the tracks
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // duration of a tick is numerator / denominator nanoseconds
+    let smpte = division & 0x8000 != 0;
+    let (mut numerator, denominator) = if smpte {
+        // negative frames per second and ticks per frame, 29 denotes 29.97 fps drop frame
+        let fps_hundredths = match ((division >> 8) as i8).checked_neg() {
+            Some(29) => 2997,
+            Some(fps @ (24 | 25 | 30)) => fps as u128 * 100,
+            _ => {
+                return Err(Box::from(format!(
+                    "SMPTE division {:#06x} is not supported",
+                    division
+                )))
+            }
+        };
+        (
+            100_000_000_000u128,
+            fps_hundredths * (division & 0xff).max(1) as u128,
+        )
+    } else {
+        (DEFAULT_TEMPO as u128 * 1000, division.max(1) as u128)
+    };
+
+    let mut ret = Vec::new();
+    // times are computed relative to the last tempo change, so rounding errors do not accumulate
+    let mut tempo_tick = 0u64;
+    let mut tempo_time_ns = 0u128;
+    for (tick, event) in events {
+        let time_ns = tempo_time_ns + (tick - tempo_tick) as u128 * numerator / denominator;
+        match event {
+            TrackEvent::Message(message) => ret.push(Event {
+                time: Duration::from_nanos(time_ns as u64),
+                message,
+            }),
+            // tempo changes do not affect SMPTE time
+            TrackEvent::Tempo(tempo) if !smpte => {
+                tempo_tick = tick;
+                tempo_time_ns = time_ns;
+                numerator = tempo as u128 * 1000;
+            }
+            TrackEvent::Tempo(_) => {}
+        }
+    }
+    Ok(ret)
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<Event>, Box<dyn Error>> {
+    read_events(&std::fs::read(path)?)
+}
+
+/// Writes a Type 0 Standard MIDI File, delta times are derived from the message timestamps
+pub struct SmfWriter<W: Write + Seek> {
+    writer: W,
+    track_length_position: u64,
+    track_length: u32,
+    first_timestamp: Option<u64>,
+    last_tick: u64,
+}
+
+impl SmfWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> SmfWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(b"MThd")?;
+        writer.write_all(&6u32.to_be_bytes())?;
+        writer.write_all(&0u16.to_be_bytes())?;
+        writer.write_all(&1u16.to_be_bytes())?;
+        writer.write_all(&DEFAULT_PPQ.to_be_bytes())?;
+
+        writer.write_all(b"MTrk")?;
+        let track_length_position = writer.stream_position()?;
+        writer.write_all(&0u32.to_be_bytes())?;
+
+        let mut ret = Self {
+            writer,
+            track_length_position,
+            track_length: 0,
+            first_timestamp: None,
+            last_tick: 0,
+        };
+
+        let tempo = DEFAULT_TEMPO.to_be_bytes();
+        ret.write_event(0, &[0xff, 0x51, 0x03, tempo[1], tempo[2], tempo[3]])?;
+        Ok(ret)
+    }
+
+    fn write_event(&mut self, delta: u32, event: &[u8]) -> std::io::Result<()> {
+        let delta_length = write_variable_length(&mut self.writer, delta)?;
+        self.writer.write_all(event)?;
+        self.track_length += (delta_length + event.len()) as u32;
+        Ok(())
+    }
+
+    /// `timestamp` in microseconds
+    pub fn write_message(&mut self, timestamp: u64, message: &[u8]) -> std::io::Result<()> {
+        if message.is_empty() {
+            return Ok(());
+        }
+
+        // ticks are computed from the absolute time, so rounding errors do not accumulate
+        let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+        let elapsed = timestamp.saturating_sub(first_timestamp) as u128;
+        let tick = (elapsed * DEFAULT_PPQ as u128 / DEFAULT_TEMPO as u128) as u64;
+        let delta = tick.saturating_sub(self.last_tick) as u32;
+        self.last_tick = self.last_tick.max(tick);
+
+        let mut event = Vec::with_capacity(message.len() + 5);
+        match message[0] {
+            0x80..=0xef => event.extend_from_slice(message),
+            0xf0 => {
+                event.push(0xf0);
+                write_variable_length(&mut event, (message.len() - 1) as u32)?;
+                event.extend_from_slice(&message[1..]);
+            }
+            // system common and realtime messages can only be stored as escape sequences
+            _ => {
+                event.push(0xf7);
+                write_variable_length(&mut event, message.len() as u32)?;
+                event.extend_from_slice(message);
+            }
+        }
+        self.write_event(delta, &event)
+    }
+
+    /// Writes the end of track event and the final track length
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.write_event(0, &[0xff, 0x2f, 0x00])?;
+
+        let end = self.writer.stream_position()?;
+        self.writer
+            .seek(SeekFrom::Start(self.track_length_position))?;
+        self.writer.write_all(&self.track_length.to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::smf::{read_events, write_variable_length, Event, SmfWriter};
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_variable_length() {
+        for (value, expected) in [
+            (0u32, vec![0x00]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x81, 0x00]),
+            (0x2000, vec![0xc0, 0x00]),
+            (0x0fffffff, vec![0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            let mut out = Vec::new();
+            assert_eq!(
+                write_variable_length(&mut out, value).unwrap(),
+                expected.len()
+            );
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn test_smf_writer() {
+        let mut writer = SmfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.write_message(1_000_000, &[0x90, 60, 100]).unwrap();
+        // 250ms at 120 BPM and 960 PPQ are 480 ticks
+        writer.write_message(1_250_000, &[0x80, 60, 0]).unwrap();
+        writer
+            .write_message(1_250_000, &[0xf0, 0x7d, 0x01, 0xf7])
+            .unwrap();
+        writer.write_message(1_250_000, &[0xf8]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(&data[..14], b"MThd\0\0\0\x06\0\0\0\x01\x03\xc0");
+        assert_eq!(&data[14..18], b"MTrk");
+        let track = &data[22..];
+        assert_eq!(
+            u32::from_be_bytes(data[18..22].try_into().unwrap()) as usize,
+            track.len()
+        );
+        assert_eq!(
+            track,
+            [
+                0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // tempo
+                0x00, 0x90, 60, 100, // NoteOn
+                0x83, 0x60, 0x80, 60, 0, // NoteOff after 480 ticks
+                0x00, 0xf0, 0x03, 0x7d, 0x01, 0xf7, // SysEx
+                0x00, 0xf7, 0x01, 0xf8, // escaped clock
+                0x00, 0xff, 0x2f, 0x00, // end of track
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_written_file() {
+        let mut writer = SmfWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.write_message(500, &[0x90, 60, 100]).unwrap();
+        writer.write_message(250_500, &[0x80, 60, 0]).unwrap();
+        writer
+            .write_message(500_500, &[0xf0, 0x7d, 0x01, 0xf7])
+            .unwrap();
+        writer.write_message(500_500, &[0xfa]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(
+            read_events(&data).unwrap(),
+            vec![
+                Event {
+                    time: Duration::ZERO,
+                    message: vec![0x90, 60, 100]
+                },
+                Event {
+                    time: Duration::from_millis(250),
+                    message: vec![0x80, 60, 0]
+                },
+                Event {
+                    time: Duration::from_millis(500),
+                    message: vec![0xf0, 0x7d, 0x01, 0xf7]
+                },
+                Event {
+                    time: Duration::from_millis(500),
+                    message: vec![0xfa]
+                },
+            ]
+        );
+    }
+
+    fn chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = chunk_type.to_vec();
+        ret.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    #[test]
+    fn test_read_type_1() {
+        // 96 PPQ, tempo track switches from 120 to 60 BPM after one quarter note
+        let mut data = chunk(b"MThd", &[0, 1, 0, 2, 0, 96]);
+        data.extend(chunk(
+            b"MTrk",
+            &[
+                0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 120 BPM
+                0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 BPM
+                0x00, 0xff, 0x2f, 0x00,
+            ],
+        ));
+        data.extend(chunk(b"XFIH", &[1, 2, 3]));
+        data.extend(chunk(
+            b"MTrk",
+            &[
+                0x00, 0x91, 60, 100, // NoteOn
+                0x60, 64, 100, // running status, after one quarter note
+                0x30, 0xc1, 5, // program change, after half a quarter note at 60 BPM
+                0x00, 0xff, 0x2f, 0x00,
+            ],
+        ));
+
+        let events = read_events(&data).unwrap();
+        let times: Vec<_> = events.iter().map(|event| event.time).collect();
+        assert_eq!(
+            times,
+            [
+                Duration::ZERO,
+                Duration::from_millis(500),
+                Duration::from_millis(1000)
+            ]
+        );
+        assert_eq!(events[1].message, [0x91, 64, 100]);
+        assert_eq!(events[2].message, [0xc1, 5]);
+
+        assert!(read_events(b"RIFF").is_err());
+        assert!(read_events(&data[..data.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_read_smpte() {
+        // 25 fps with 40 ticks per frame, one tick per millisecond
+        let track = chunk(b"MTrk", &[0x00, 0x90, 60, 100, 0x64, 0x80, 60, 0]);
+        let mut data = chunk(b"MThd", &[0, 0, 0, 1, 0xe7, 40]);
+        data.extend(track.clone());
+        let events = read_events(&data).unwrap();
+        assert_eq!(events[1].time, Duration::from_millis(100));
+
+        for frames in [0x80, 0xe6] {
+            let mut data = chunk(b"MThd", &[0, 0, 0, 1, frames, 40]);
+            data.extend(track.clone());
+            assert!(read_events(&data).is_err(), "{:#x}", frames);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::generator::{Generator, GeneratorOptions};
+use crate::loopback_timer::LoopbackTimer;
+use crate::transport::Transport;
+use crate::utils::{wait_for_sigint, Sender};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::time::{interval, sleep, Instant};
+use wmidi::MidiMessage;
+
+pub struct StressOptions {
+    /// Initial rate (in messages per second)
+    pub start_rate: f64,
+    /// Rate of the last step (in messages per second)
+    pub max_rate: f64,
+    /// Factor by which the rate is increased after each successful step
+    pub rate_factor: f64,
+    pub step_duration: Duration,
+    pub note_duration: Duration,
+    /// Messages that are not received back within this time are counted as lost
+    pub loss_timeout: Duration,
+    /// Highest acceptable ratio of lost messages
+    pub max_loss: f64,
+    /// Highest acceptable 99th percentile of the latency
+    pub max_latency: Duration,
+}
+
+pub struct StepReport {
+    /// Target rate (in messages per second)
+    pub rate: f64,
+    /// Rate that was actually sent (in messages per second)
+    pub sent_rate: f64,
+    pub sent: u64,
+    pub lost: u64,
+    pub p99: Option<Duration>,
+    pub passed: bool,
+}
+
+impl StepReport {
+    fn loss(&self) -> f64 {
+        self.lost as f64 / self.sent.max(1) as f64
+    }
+}
+
+async fn run_step(
+    transport: &dyn Transport,
+    options: &StressOptions,
+    output_device: &str,
+    rate: f64,
+    timer: Arc<LoopbackTimer>,
+) -> Result<StepReport, Box<dyn std::error::Error>> {
+    // each note consists of a NoteOn and a NoteOff
+    let generator = Generator::new(
+        GeneratorOptions {
+            note_duration: options.note_duration,
+            duration_between_notes: Duration::from_secs_f64(2.0 / rate),
+            unique_probes: true,
+            seed: rand::random(),
+            ..Default::default()
+        },
+        Sender::Connection(transport.connect_output(output_device)?),
+        Some(timer.clone()),
+    );
+
+    let start = Instant::now();
+    let mut ticks = interval(generator.note_offset());
+    while start.elapsed() < options.step_duration {
+        ticks.tick().await;
+        generator.schedule_note().await;
+    }
+    let elapsed = start.elapsed();
+
+    // wait for the pending NoteOffs and all echoes, anything still in flight afterwards is lost
+    sleep(options.note_duration + options.loss_timeout).await;
+
+    let reliability = timer.reliability();
+    let p99 = timer.summary().map(|summary| summary.p99);
+    let mut report = StepReport {
+        rate,
+        sent_rate: reliability.sent as f64 / (elapsed + options.note_duration).as_secs_f64(),
+        sent: reliability.sent,
+        lost: reliability.lost + reliability.in_flight,
+        p99,
+        passed: false,
+    };
+    report.passed = report.sent > 0
+        && report.loss() <= options.max_loss
+        && p99.is_some_and(|p99| p99 <= options.max_latency);
+    Ok(report)
+}
+
+fn print_step(report: &StepReport) {
+    println!(
+        "{:>10.1} {:>10.1} {:>8} {:>6} {:>7.2}% {:>12} {}",
+        report.rate,
+        report.sent_rate,
+        report.sent,
+        report.lost,
+        report.loss() * 100.0,
+        report
+            .p99
+            .map_or("-".to_string(), |p99| format!("{:#?}", p99)),
+        if report.passed { "ok" } else { "failed" }
+    );
+}
+
+fn run_stress_test(
+    transport: &dyn Transport,
+    options: &StressOptions,
+    input_device: &str,
+    output_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<Vec<StepReport>, Box<dyn std::error::Error>> {
+    if !(options.start_rate.is_finite() && options.start_rate > 0.0) {
+        return Err(Box::from("The start rate must be positive"));
+    }
+    if !(options.rate_factor.is_finite() && options.rate_factor > 1.0) {
+        return Err(Box::from("The rate factor must be greater than 1"));
+    }
+    if !(options.max_rate.is_finite() && options.max_rate >= options.start_rate) {
+        return Err(Box::from(
+            "The maximum rate must not be below the start rate",
+        ));
+    }
+    let current_timer = Arc::new(Mutex::new(None::<Arc<LoopbackTimer>>));
+
+    let captured_timer = current_timer.clone();
+    let _in_connection = transport.connect_input(
+        input_device,
+        Box::new(move |stamp, message: &[u8]| {
+            let timer = captured_timer.lock().unwrap().clone();
+            if let (Some(timer), Ok(midi_msg)) = (timer, MidiMessage::from_bytes(message)) {
+                timer.process_received_message(&midi_msg, stamp);
+            }
+        }),
+    )?;
+
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        let mut reports = Vec::new();
+        let run = async {
+            let mut rate = options.start_rate;
+            while rate <= options.max_rate {
+                let timer = LoopbackTimer::new(options.loss_timeout, false);
+                *current_timer.lock().unwrap() = Some(timer.clone());
+
+                let report = run_step(transport, options, output_device, rate, timer).await?;
+                print_step(&report);
+                let passed = report.passed;
+                reports.push(report);
+                if !passed {
+                    break;
+                }
+                rate *= options.rate_factor;
+            }
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+
+        tokio::select! {
+            result = run => result?,
+            _ = stop => {},
+        }
+        Ok(reports)
+    })
+}
+
+/// Highest target rate of all steps that passed
+pub fn max_sustainable_rate(reports: &[StepReport]) -> Option<f64> {
+    reports
+        .iter()
+        .take_while(|report| report.passed)
+        .map(|report| report.rate)
+        .last()
+}
+
+pub fn stress_test(
+    transport: &dyn Transport,
+    options: &StressOptions,
+    input_device: &str,
+    output_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "{:>10} {:>10} {:>8} {:>6} {:>8} {:>12}",
+        "Rate", "Sent rate", "Sent", "Lost", "Loss", "p99"
+    );
+    let reports = run_stress_test(
+        transport,
+        options,
+        input_device,
+        output_device,
+        wait_for_sigint(),
+    )?;
+
+    match max_sustainable_rate(&reports) {
+        Some(rate) => println!("Maximum sustainable rate: {:.1} messages/s", rate),
+        None => println!("No sustainable rate found"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stress::{max_sustainable_rate, run_stress_test, StressOptions};
+    use crate::transport::LoopbackTransport;
+    use std::time::Duration;
+
+    fn options() -> StressOptions {
+        StressOptions {
+            start_rate: 100.0,
+            max_rate: 400.0,
+            rate_factor: 2.0,
+            step_duration: Duration::from_millis(100),
+            note_duration: Duration::from_millis(5),
+            loss_timeout: Duration::from_millis(20),
+            max_loss: 0.0,
+            max_latency: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn test_stress() {
+        let transport = LoopbackTransport::new();
+        let reports = run_stress_test(
+            &transport,
+            &options(),
+            "loop",
+            "loop",
+            std::future::pending(),
+        )
+        .unwrap();
+
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|report| report.passed));
+        assert!(reports[2].sent > reports[0].sent);
+        assert_eq!(max_sustainable_rate(&reports), Some(400.0));
+    }
+
+    #[test]
+    fn test_stress_loss() {
+        let transport = LoopbackTransport::new();
+        let reports = run_stress_test(
+            &transport,
+            &options(),
+            "loop",
+            "elsewhere",
+            std::future::pending(),
+        )
+        .unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert!(!reports[0].passed);
+        assert_eq!(reports[0].lost, reports[0].sent);
+        assert_eq!(max_sustainable_rate(&reports), None);
+    }
+
+    #[test]
+    fn test_invalid_rates() {
+        let transport = LoopbackTransport::new();
+        for options in [
+            StressOptions {
+                start_rate: 0.0,
+                ..options()
+            },
+            StressOptions {
+                rate_factor: 1.0,
+                ..options()
+            },
+            StressOptions {
+                max_rate: 50.0,
+                ..options()
+            },
+            StressOptions {
+                max_rate: f64::NAN,
+                ..options()
+            },
+        ] {
+            let result = run_stress_test(&transport, &options, "loop", "loop", async {});
+            assert!(result.is_err());
+        }
+    }
+}
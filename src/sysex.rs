@@ -0,0 +1,314 @@
+use crate::analysis::Summary;
+use crate::transport::{OutputConnection, Transport};
+use crate::utils::wait_for_sigint;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::runtime::Builder;
+use tokio::sync::mpsc;
+use tokio::time::timeout_at;
+
+/// Manufacturer id reserved for non-commercial use
+const MANUFACTURER_ID: u8 = 0x7d;
+
+/// Bytes used to encode the sequence number (7 bits each)
+const SEQUENCE_BYTES: usize = 5;
+
+pub const MIN_SIZE: usize = SEQUENCE_BYTES + 3;
+pub const MAX_SIZE: usize = 65536;
+
+/// Builds a SysEx message of `size` bytes (including F0/F7) that carries the sequence number,
+/// followed by a payload pattern derived from it
+pub fn make_message(sequence: u32, size: usize) -> Vec<u8> {
+    assert!((MIN_SIZE..=MAX_SIZE).contains(&size));
+
+    let mut ret = Vec::with_capacity(size);
+    ret.push(0xf0);
+    ret.push(MANUFACTURER_ID);
+    for i in 0..SEQUENCE_BYTES {
+        ret.push(((sequence as u64 >> (7 * i)) & 0x7f) as u8);
+    }
+    for i in 0..size - MIN_SIZE {
+        ret.push(((sequence as usize + i) & 0x7f) as u8);
+    }
+    ret.push(0xf7);
+    ret
+}
+
+pub fn parse_sequence(message: &[u8]) -> Option<u32> {
+    if message.len() < MIN_SIZE || message[0] != 0xf0 || message[1] != MANUFACTURER_ID {
+        return None;
+    }
+
+    let sequence = message[2..2 + SEQUENCE_BYTES]
+        .iter()
+        .rev()
+        .fold(0u64, |acc, byte| (acc << 7) | (*byte & 0x7f) as u64);
+    Some(sequence as u32)
+}
+
+pub struct SysexOptions {
+    /// Message sizes including the F0/F7 framing
+    pub sizes: Vec<usize>,
+    pub messages_per_size: u32,
+    /// Messages that are not received back within this time are counted as lost
+    pub timeout: Duration,
+    pub print: bool,
+}
+
+pub struct SizeReport {
+    pub size: usize,
+    pub sent: u32,
+    pub received: u32,
+    /// Received with the right sequence number, but different content
+    pub corrupted: u32,
+    pub lost: u32,
+    pub summary: Option<Summary>,
+    /// Bytes per second of correctly received messages
+    pub throughput: f64,
+}
+
+struct Received {
+    time: Instant,
+    message: Vec<u8>,
+}
+
+async fn run_size(
+    output: &mut Box<dyn OutputConnection>,
+    receiver: &mut mpsc::UnboundedReceiver<Received>,
+    options: &SysexOptions,
+    size: usize,
+    first_sequence: u32,
+) -> Result<SizeReport, Box<dyn std::error::Error>> {
+    let mut report = SizeReport {
+        size,
+        sent: 0,
+        received: 0,
+        corrupted: 0,
+        lost: 0,
+        summary: None,
+        throughput: 0.0,
+    };
+    let mut latencies = Vec::new();
+    let start = Instant::now();
+
+    for sequence in first_sequence..first_sequence + options.messages_per_size {
+        let message = make_message(sequence, size);
+        let send_time = Instant::now();
+        output.send(&message)?;
+        report.sent += 1;
+
+        let deadline = send_time + options.timeout;
+        loop {
+            let received = match timeout_at(deadline.into(), receiver.recv()).await {
+                Ok(Some(received)) => received,
+                _ => {
+                    report.lost += 1;
+                    break;
+                }
+            };
+
+            // late echoes of earlier messages are skipped
+            if parse_sequence(&received.message) != Some(sequence) {
+                continue;
+            }
+
+            if received.message == message {
+                report.received += 1;
+                latencies.push(received.time - send_time);
+            } else {
+                report.corrupted += 1;
+            }
+            if options.print {
+                println!(
+                    "Received {} bytes (#{}) after {:#?}",
+                    received.message.len(),
+                    sequence,
+                    received.time - send_time
+                );
+            }
+            break;
+        }
+    }
+
+    report.summary = Summary::new(&latencies);
+    report.throughput = (report.received as usize * size) as f64 / start.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+fn run_sysex_test(
+    transport: &dyn Transport,
+    options: &SysexOptions,
+    input_device: &str,
+    output_device: &str,
+    stop: impl Future<Output = ()>,
+) -> Result<Vec<SizeReport>, Box<dyn std::error::Error>> {
+    // each message has its own sequence number
+    let total = u32::try_from(options.sizes.len())
+        .ok()
+        .and_then(|sizes| sizes.checked_mul(options.messages_per_size));
+    if total.is_none() {
+        return Err(Box::from(
+            "Too many messages, the sequence numbers would overflow",
+        ));
+    }
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let _in_connection = transport.connect_input(
+        input_device,
+        Box::new(move |_stamp, message: &[u8]| {
+            let _ = sender.send(Received {
+                time: Instant::now(),
+                message: message.to_vec(),
+            });
+        }),
+    )?;
+    let mut output = transport.connect_output(output_device)?;
+
+    let rt = Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(async {
+        let mut reports = Vec::new();
+        let run = async {
+            let mut sequence = 0;
+            for size in &options.sizes {
+                let report = run_size(&mut output, &mut receiver, options, *size, sequence).await?;
+                sequence += options.messages_per_size;
+                print_report(&report);
+                reports.push(report);
+            }
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+
+        tokio::select! {
+            result = run => result?,
+            _ = stop => {},
+        }
+        Ok(reports)
+    })
+}
+
+fn print_report(report: &SizeReport) {
+    let percentiles = match &report.summary {
+        Some(summary) => format!(
+            "{:>12} {:>12} {:>12}",
+            format!("{:#?}", summary.p50),
+            format!("{:#?}", summary.p99),
+            format!("{:#?}", summary.max)
+        ),
+        None => format!("{:>12} {:>12} {:>12}", "-", "-", "-"),
+    };
+    println!(
+        "{:>6} {:>6} {:>8} {:>9} {:>6} {} {:>12.0}",
+        report.size,
+        report.sent,
+        report.received,
+        report.corrupted,
+        report.lost,
+        percentiles,
+        report.throughput
+    );
+}
+
+pub fn sysex_test(
+    transport: &dyn Transport,
+    options: &SysexOptions,
+    input_device: &str,
+    output_device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "{:>6} {:>6} {:>8} {:>9} {:>6} {:>12} {:>12} {:>12} {:>12}",
+        "Size", "Sent", "Received", "Corrupted", "Lost", "p50", "p99", "Max", "Bytes/s"
+    );
+    run_sysex_test(
+        transport,
+        options,
+        input_device,
+        output_device,
+        wait_for_sigint(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sysex::{make_message, parse_sequence, run_sysex_test, SysexOptions, MAX_SIZE};
+    use crate::transport::LoopbackTransport;
+    use std::time::Duration;
+
+    #[test]
+    fn test_make_message() {
+        let message = make_message(0x12345678, 12);
+        assert_eq!(message.len(), 12);
+        assert_eq!(message[0], 0xf0);
+        assert_eq!(message[11], 0xf7);
+        assert!(message[1..11].iter().all(|byte| *byte < 0x80));
+        assert_eq!(parse_sequence(&message), Some(0x12345678));
+
+        assert_eq!(make_message(7, MAX_SIZE).len(), MAX_SIZE);
+        assert_eq!(parse_sequence(&[0x90, 60, 100]), None);
+    }
+
+    #[test]
+    fn test_sysex_roundtrip() {
+        let transport = LoopbackTransport::new();
+        let reports = run_sysex_test(
+            &transport,
+            &SysexOptions {
+                sizes: vec![8, 300, MAX_SIZE],
+                messages_per_size: 3,
+                timeout: Duration::from_millis(100),
+                print: false,
+            },
+            "loop",
+            "loop",
+            std::future::pending(),
+        )
+        .unwrap();
+
+        assert_eq!(reports.len(), 3);
+        for report in reports {
+            assert_eq!(report.sent, 3);
+            assert_eq!(report.received, 3);
+            assert_eq!(report.corrupted + report.lost, 0);
+            assert_eq!(report.summary.unwrap().count, 3);
+            assert!(report.throughput > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_sysex_count_overflow() {
+        let transport = LoopbackTransport::new();
+        let result = run_sysex_test(
+            &transport,
+            &SysexOptions {
+                sizes: vec![8, 16],
+                messages_per_size: u32::MAX,
+                timeout: Duration::from_millis(10),
+                print: false,
+            },
+            "loop",
+            "loop",
+            async {},
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_sysex_lost() {
+        let transport = LoopbackTransport::new();
+        let reports = run_sysex_test(
+            &transport,
+            &SysexOptions {
+                sizes: vec![16],
+                messages_per_size: 2,
+                timeout: Duration::from_millis(10),
+                print: false,
+            },
+            "loop",
+            "elsewhere",
+            std::future::pending(),
+        )
+        .unwrap();
+
+        assert_eq!(reports[0].lost, 2);
+        assert!(reports[0].summary.is_none());
+    }
+}
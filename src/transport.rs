@@ -0,0 +1,363 @@
+use crate::utils::{resolve_input_port, resolve_output_port};
+use midir::{Ignore, MidiInput, MidiOutput, MidiOutputConnection};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Callback invoked for each received message with a timestamp (in microseconds) and the raw bytes
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send + 'static>;
+
+/// Keeps an input subscription alive; the callback is disconnected when it is dropped
+pub type InputConnection = Box<dyn Send>;
+
+pub trait OutputConnection: Send {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+/// Source and sink of midi messages, addressed by port name
+pub trait Transport {
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    fn connect_input(
+        &self,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<InputConnection, Box<dyn Error>>;
+
+    fn connect_output(&self, port_name: &str) -> Result<Box<dyn OutputConnection>, Box<dyn Error>>;
+}
+
+/// Transport using the system midi ports via midir
+pub struct MidirTransport {
+    /// Publish virtual ports with the requested names instead of connecting to existing ones
+    pub virtual_ports: bool,
+}
+
+impl OutputConnection for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(MidiOutputConnection::send(self, message)?)
+    }
+}
+
+impl Transport for MidirTransport {
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let midi_in = MidiInput::new("midi-toolbox input")?;
+        let mut ret = Vec::new();
+        for port in midi_in.ports() {
+            ret.push(midi_in.port_name(&port)?);
+        }
+        Ok(ret)
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let midi_out = MidiOutput::new("midi-toolbox output")?;
+        let mut ret = Vec::new();
+        for port in midi_out.ports() {
+            ret.push(midi_out.port_name(&port)?);
+        }
+        Ok(ret)
+    }
+
+    fn connect_input(
+        &self,
+        port_name: &str,
+        mut callback: InputCallback,
+    ) -> Result<InputConnection, Box<dyn Error>> {
+        let mut midi_in = MidiInput::new("midi-toolbox input")?;
+        midi_in.ignore(Ignore::None);
+
+        let callback = move |stamp, message: &[u8], _: &mut ()| callback(stamp, message);
+        if self.virtual_ports {
+            return create_virtual_input(midi_in, port_name, callback);
+        }
+
+        let in_port = resolve_input_port(&midi_in, port_name)?;
+        let connection = midi_in.connect(&in_port, "MidiToolbox input", callback, ())?;
+        Ok(Box::new(connection))
+    }
+
+    fn connect_output(&self, port_name: &str) -> Result<Box<dyn OutputConnection>, Box<dyn Error>> {
+        let midi_out = MidiOutput::new("midi-toolbox output")?;
+        if self.virtual_ports {
+            return create_virtual_output(midi_out, port_name);
+        }
+
+        let out_port = resolve_output_port(&midi_out, port_name)?;
+        let connection = midi_out.connect(&out_port, "MidiToolbox output")?;
+        Ok(Box::new(connection))
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual_input(
+    midi_in: MidiInput,
+    port_name: &str,
+    callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<InputConnection, Box<dyn Error>> {
+    use midir::os::unix::VirtualInput;
+    Ok(Box::new(midi_in.create_virtual(port_name, callback, ())?))
+}
+
+#[cfg(unix)]
+fn create_virtual_output(
+    midi_out: MidiOutput,
+    port_name: &str,
+) -> Result<Box<dyn OutputConnection>, Box<dyn Error>> {
+    use midir::os::unix::VirtualOutput;
+    Ok(Box::new(midi_out.create_virtual(port_name)?))
+}
+
+#[cfg(not(unix))]
+fn create_virtual_input(
+    _midi_in: MidiInput,
+    _port_name: &str,
+    _callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<InputConnection, Box<dyn Error>> {
+    Err(Box::from(
+        "Virtual ports are not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn create_virtual_output(
+    _midi_out: MidiOutput,
+    _port_name: &str,
+) -> Result<Box<dyn OutputConnection>, Box<dyn Error>> {
+    Err(Box::from(
+        "Virtual ports are not supported on this platform",
+    ))
+}
+
+struct Subscriber {
+    id: usize,
+    port_name: String,
+    callback: Arc<Mutex<InputCallback>>,
+}
+
+struct LoopbackBus {
+    start: Instant,
+    next_id: AtomicUsize,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl LoopbackBus {
+    fn dispatch(&self, port_name: &str, stamp: u64, message: &[u8]) {
+        // callbacks may send to other ports, so do not hold the subscriber lock while dispatching
+        let callbacks: Vec<_> = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.port_name == port_name)
+            .map(|s| s.callback.clone())
+            .collect();
+
+        for callback in callbacks {
+            (callback.lock().unwrap())(stamp, message);
+        }
+    }
+}
+
+type Delivery = (Arc<LoopbackBus>, String, u64, Vec<u8>);
+
+thread_local! {
+    /// Messages sent by callbacks while this thread dispatches, delivered after the running
+    /// callback returns, so that a callback can send to its own port without deadlocking
+    static DEFERRED: RefCell<Option<VecDeque<Delivery>>> = const { RefCell::new(None) };
+}
+
+/// Ends the dispatch of this thread, also if a callback panics
+struct DispatchGuard;
+
+impl Drop for DispatchGuard {
+    fn drop(&mut self) {
+        DEFERRED.with(|deferred| *deferred.borrow_mut() = None);
+    }
+}
+
+/// In-process transport: messages sent to an output port are delivered to all inputs of the same
+/// name. Ports are created on demand.
+#[derive(Clone)]
+pub struct LoopbackTransport {
+    bus: Arc<LoopbackBus>,
+}
+
+impl LoopbackTransport {
+    pub fn new() -> Self {
+        Self {
+            bus: Arc::new(LoopbackBus {
+                start: Instant::now(),
+                next_id: AtomicUsize::new(0),
+                subscribers: Default::default(),
+            }),
+        }
+    }
+
+    fn port_names(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self
+            .bus
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.port_name.clone())
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+}
+
+struct LoopbackInputConnection {
+    bus: Arc<LoopbackBus>,
+    id: usize,
+}
+
+impl Drop for LoopbackInputConnection {
+    fn drop(&mut self) {
+        self.bus
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.id != self.id);
+    }
+}
+
+struct LoopbackOutputConnection {
+    bus: Arc<LoopbackBus>,
+    port_name: String,
+}
+
+impl OutputConnection for LoopbackOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let stamp = self.bus.start.elapsed().as_micros() as u64;
+
+        let delivery = (
+            self.bus.clone(),
+            self.port_name.clone(),
+            stamp,
+            message.to_vec(),
+        );
+        let delivery = DEFERRED.with(|deferred| match deferred.borrow_mut().as_mut() {
+            Some(queue) => {
+                queue.push_back(delivery);
+                None
+            }
+            None => Some(delivery),
+        });
+        let Some(mut delivery) = delivery else {
+            return Ok(());
+        };
+
+        DEFERRED.with(|deferred| *deferred.borrow_mut() = Some(VecDeque::new()));
+        let _guard = DispatchGuard;
+        loop {
+            let (bus, port_name, stamp, message) = delivery;
+            bus.dispatch(&port_name, stamp, &message);
+            match DEFERRED.with(|deferred| deferred.borrow_mut().as_mut().unwrap().pop_front()) {
+                Some(next) => delivery = next,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn input_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self.port_names())
+    }
+
+    fn output_ports(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self.port_names())
+    }
+
+    fn connect_input(
+        &self,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<InputConnection, Box<dyn Error>> {
+        let id = self.bus.next_id.fetch_add(1, Ordering::Relaxed);
+        self.bus.subscribers.lock().unwrap().push(Subscriber {
+            id,
+            port_name: port_name.to_string(),
+            callback: Arc::new(Mutex::new(callback)),
+        });
+
+        Ok(Box::new(LoopbackInputConnection {
+            bus: self.bus.clone(),
+            id,
+        }))
+    }
+
+    fn connect_output(&self, port_name: &str) -> Result<Box<dyn OutputConnection>, Box<dyn Error>> {
+        Ok(Box::new(LoopbackOutputConnection {
+            bus: self.bus.clone(),
+            port_name: port_name.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::{LoopbackTransport, Transport};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_loopback_transport() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+
+        let captured = received.clone();
+        let connection = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_, message| captured.lock().unwrap().push(message.to_vec())),
+            )
+            .unwrap();
+        assert_eq!(transport.input_ports().unwrap(), ["loop"]);
+
+        let mut output = transport.connect_output("loop").unwrap();
+        let mut other = transport.connect_output("other").unwrap();
+        output.send(&[0x90, 60, 100]).unwrap();
+        other.send(&[0x90, 61, 100]).unwrap();
+        assert_eq!(*received.lock().unwrap(), [vec![0x90, 60, 100]]);
+
+        drop(connection);
+        output.send(&[0x80, 60, 0]).unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert!(transport.input_ports().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_loopback_send_to_own_port() {
+        let transport = LoopbackTransport::new();
+        let received = Arc::new(Mutex::new(Vec::<Vec<u8>>::new()));
+
+        // answers each NoteOn with a NoteOff on the same port
+        let captured = received.clone();
+        let mut answer = transport.connect_output("loop").unwrap();
+        let _connection = transport
+            .connect_input(
+                "loop",
+                Box::new(move |_, message| {
+                    captured.lock().unwrap().push(message.to_vec());
+                    if message[0] == 0x90 {
+                        answer.send(&[0x80, message[1], 0]).unwrap();
+                    }
+                }),
+            )
+            .unwrap();
+
+        let mut output = transport.connect_output("loop").unwrap();
+        output.send(&[0x90, 60, 100]).unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            [vec![0x90, 60, 100], vec![0x80, 60, 0]]
+        );
+    }
+}
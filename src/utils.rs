@@ -1,9 +1,10 @@
-use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort};
+use crate::transport::OutputConnection;
+use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputPort};
 use std::error::Error;
 
 pub fn resolve_input_port<'a>(
     midi_in: &'a MidiInput,
-    port_name: &'a String,
+    port_name: &'a str,
 ) -> Result<MidiInputPort, Box<dyn Error>> {
     let in_ports = midi_in.ports();
     for port in in_ports {
@@ -18,7 +19,7 @@ pub fn resolve_input_port<'a>(
 
 pub fn resolve_output_port<'a>(
     midi_out: &'a MidiOutput,
-    port_name: &'a String,
+    port_name: &'a str,
 ) -> Result<MidiOutputPort, Box<dyn Error>> {
     let in_ports = midi_out.ports();
     for port in in_ports {
@@ -34,19 +35,22 @@ pub fn resolve_output_port<'a>(
 use runtime::Builder;
 use tokio::{runtime, signal};
 
+pub async fn wait_for_sigint() {
+    signal::ctrl_c()
+        .await
+        .expect("Failed to install Ctrl+C signal handler");
+}
+
 pub fn loop_until_sigint() -> Result<(), Box<dyn Error>> {
     let rt = Builder::new_current_thread().enable_all().build()?;
-    rt.block_on(async {
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C signal handler");
-    });
+    rt.block_on(wait_for_sigint());
 
     Ok(())
 }
 
 use heapless::Vec;
-use wmidi::{MidiMessage, Note};
+use std::ops::RangeInclusive;
+use wmidi::{Channel, ControlFunction, MidiMessage, Note, U7};
 
 pub fn all_notes() -> [Note; 128] {
     static ALL_NOTES: [Note; 128] = [
@@ -186,10 +190,16 @@ pub fn all_notes() -> [Note; 128] {
 #[allow(dead_code)]
 pub enum Sender {
     Function(fn(&MidiMessage) -> ()),
-    Connection(MidiOutputConnection),
+    Connection(Box<dyn OutputConnection>),
 }
 
-#[warn(dead_code)]
+/// All Notes Off (CC 123) followed by All Sound Off (CC 120)
+pub fn all_notes_off(channel: Channel) -> [MidiMessage<'static>; 2] {
+    [
+        MidiMessage::ControlChange(channel, ControlFunction::ALL_NOTES_OFF, U7::MIN),
+        MidiMessage::ControlChange(channel, ControlFunction::ALL_SOUND_OFF, U7::MIN),
+    ]
+}
 
 pub fn to_vec(midi_message: &MidiMessage) -> heapless::Vec<u8, 8> {
     let mut ret = Vec::<u8, 8>::new();
@@ -198,6 +208,86 @@ pub fn to_vec(midi_message: &MidiMessage) -> heapless::Vec<u8, 8> {
     ret
 }
 
+/// Name of a note such as `C4` or `F#2` (C4 is note 60)
+pub fn note_name(note: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Parses a note number or a note name such as `C4`, `F#2` or `Bb-1` (C4 is note 60)
+pub fn parse_note(s: &str) -> Result<u8, String> {
+    if let Ok(note) = s.parse::<u8>() {
+        return match note {
+            0..=127 => Ok(note),
+            _ => Err(format!("Note {} out of range", note)),
+        };
+    }
+
+    let error = || format!("Invalid note {:?}", s);
+    let mut chars = s.chars();
+    let pitch_class: i32 = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(error()),
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i8 = octave.parse().map_err(|_| error())?;
+    if !(-1..=9).contains(&octave) {
+        return Err(format!("Note {} out of range", s));
+    }
+
+    u8::try_from((octave as i32 + 1) * 12 + pitch_class + accidental)
+        .ok()
+        .filter(|note| *note < 128)
+        .ok_or_else(|| format!("Note {} out of range", s))
+}
+
+/// Parses a pitch class such as `A` or `F#`, notes with an octave are accepted as well
+pub fn parse_pitch_class(s: &str) -> Result<u8, String> {
+    parse_note(s)
+        .or_else(|error| parse_note(&format!("{}4", s)).map_err(|_| error))
+        .map(|note| note % 12)
+}
+
+fn parse_range(
+    s: &str,
+    parse: impl Fn(&str) -> Result<u8, String>,
+) -> Result<RangeInclusive<u8>, String> {
+    let (low, high) = match s.split_once("..") {
+        Some((low, high)) => (parse(low)?, parse(high)?),
+        None => (parse(s)?, parse(s)?),
+    };
+    if low > high {
+        return Err(format!("Empty range {:?}", s));
+    }
+    Ok(low..=high)
+}
+
+/// Parses an inclusive range of notes `LOW..HIGH`
+pub fn parse_note_range(s: &str) -> Result<RangeInclusive<u8>, String> {
+    parse_range(s, parse_note)
+}
+
+/// Parses an inclusive range of velocities `LOW..HIGH`, velocity 0 would be a NoteOff
+pub fn parse_velocity_range(s: &str) -> Result<RangeInclusive<u8>, String> {
+    parse_range(s, |velocity| match velocity.parse::<u8>() {
+        Ok(velocity @ 1..=127) => Ok(velocity),
+        _ => Err(format!("Invalid velocity {:?}", velocity)),
+    })
+}
+
 #[cfg(target_os = "linux")]
 use libc::{self, sched_param, sched_setscheduler};
 #[cfg(target_os = "linux")]
@@ -232,4 +322,39 @@ mod tests {
 
         assert_eq!(MidiMessage::from_bytes(&vec).ok().unwrap(), noteon);
     }
+
+    #[test]
+    fn parse_note() {
+        assert_eq!(utils::parse_note("60"), Ok(60));
+        assert_eq!(utils::parse_note("C4"), Ok(60));
+        assert_eq!(utils::parse_note("c#4"), Ok(61));
+        assert_eq!(utils::parse_note("Bb-1"), Ok(10));
+        assert_eq!(
+            utils::parse_note("Cb-1"),
+            Err("Note Cb-1 out of range".to_string())
+        );
+        assert_eq!(utils::parse_note("C-1"), Ok(0));
+        assert_eq!(utils::parse_note("G9"), Ok(127));
+        assert!(utils::parse_note("G#9").is_err());
+        assert!(utils::parse_note("128").is_err());
+        assert!(utils::parse_note("H2").is_err());
+        assert!(utils::parse_note("C10").is_err());
+        assert!(utils::parse_note("C-2").is_err());
+        assert!(utils::parse_note("C2147483647").is_err());
+        assert!(utils::parse_note("C178956970").is_err());
+
+        assert_eq!(utils::parse_pitch_class("A"), Ok(9));
+        for note in 0..=127 {
+            assert_eq!(utils::parse_note(&utils::note_name(note)), Ok(note));
+        }
+        assert_eq!(utils::note_name(61), "C#4");
+        assert_eq!(utils::parse_pitch_class("Bb2"), Ok(10));
+        assert!(utils::parse_pitch_class("X").is_err());
+
+        assert_eq!(utils::parse_note_range("C2..C6"), Ok(36..=84));
+        assert_eq!(utils::parse_note_range("A0"), Ok(21..=21));
+        assert!(utils::parse_note_range("C6..C2").is_err());
+        assert_eq!(utils::parse_velocity_range("1..127"), Ok(1..=127));
+        assert!(utils::parse_velocity_range("0..127").is_err());
+    }
 }